@@ -1,21 +1,116 @@
 use anyhow::{Context, Result};
+use humantime::format_duration;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::wake_mode::WakeMode;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(try_from = "RawConfig")]
 pub struct Config {
     pub nas_mac: String,
     pub nas_ip: String,
     pub router_ip: String,
     pub heartbeat_url: String,
-    pub check_interval_secs: u64,
-    pub idle_threshold_mins: u32,
-    pub heartbeat_timeout_secs: u64,
+    #[serde(with = "humantime_serde")]
+    pub check_interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub idle_threshold: Duration,
+    #[serde(with = "humantime_serde")]
+    pub heartbeat_timeout: Duration,
     #[serde(default)]
     pub wake_mode: WakeMode,
+    /// SHA-256 fingerprint of the server's certificate; when set, only a
+    /// server presenting exactly this certificate is trusted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_cert_fingerprint: Option<String>,
+    /// mDNS instance name or ID of the server; when set, `nas_ip`,
+    /// `heartbeat_url` and `nas_mac` follow the server's advertisement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+}
+
+/// On-disk representation accepting both human-readable durations ("90s",
+/// "10m", "1h30m") and the legacy `_secs`/`_mins` integer keys
+#[derive(Deserialize)]
+struct RawConfig {
+    nas_mac: String,
+    nas_ip: String,
+    router_ip: String,
+    heartbeat_url: String,
+    #[serde(default, with = "humantime_serde")]
+    check_interval: Option<Duration>,
+    check_interval_secs: Option<u64>,
+    #[serde(default, with = "humantime_serde")]
+    idle_threshold: Option<Duration>,
+    idle_threshold_mins: Option<u64>,
+    #[serde(default, with = "humantime_serde")]
+    heartbeat_timeout: Option<Duration>,
+    heartbeat_timeout_secs: Option<u64>,
+    #[serde(default)]
+    wake_mode: WakeMode,
+    #[serde(default)]
+    server_cert_fingerprint: Option<String>,
+    #[serde(default)]
+    server_name: Option<String>,
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = String;
+
+    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        fn resolve(
+            value: Option<Duration>,
+            legacy: Option<u64>,
+            legacy_unit_secs: u64,
+            key: &str,
+        ) -> Result<Duration, String> {
+            value
+                .or_else(|| legacy.map(|v| Duration::from_secs(v * legacy_unit_secs)))
+                .ok_or_else(|| format!("missing field `{key}`"))
+        }
+
+        if let Some(fingerprint) = &raw.server_cert_fingerprint {
+            if nas_boot_protocol::parse_fingerprint(fingerprint).is_none() {
+                return Err(format!(
+                    "invalid `server_cert_fingerprint` '{fingerprint}', expected 32 hex bytes"
+                ));
+            }
+            if !raw.heartbeat_url.starts_with("https://") {
+                return Err("`server_cert_fingerprint` requires an https `heartbeat_url`".to_string());
+            }
+        }
+
+        Ok(Self {
+            nas_mac: raw.nas_mac,
+            nas_ip: raw.nas_ip,
+            router_ip: raw.router_ip,
+            heartbeat_url: raw.heartbeat_url,
+            check_interval: resolve(
+                raw.check_interval,
+                raw.check_interval_secs,
+                1,
+                "check_interval",
+            )?,
+            idle_threshold: resolve(
+                raw.idle_threshold,
+                raw.idle_threshold_mins,
+                60,
+                "idle_threshold",
+            )?,
+            heartbeat_timeout: resolve(
+                raw.heartbeat_timeout,
+                raw.heartbeat_timeout_secs,
+                1,
+                "heartbeat_timeout",
+            )?,
+            wake_mode: raw.wake_mode,
+            server_cert_fingerprint: raw.server_cert_fingerprint,
+            server_name: raw.server_name,
+        })
+    }
 }
 
 impl Default for Config {
@@ -24,11 +119,13 @@ impl Default for Config {
             nas_mac: "00:08:9B:DB:EF:9A".to_string(),
             nas_ip: "192.168.42.2".to_string(),
             router_ip: "192.168.42.1".to_string(),
-            heartbeat_url: "http://192.168.42.2:8090/heartbeat".to_string(),
-            check_interval_secs: 60, // Increased from 30 to 60 seconds to reduce CPU usage
-            idle_threshold_mins: 5,
-            heartbeat_timeout_secs: 5,
+            heartbeat_url: "http://192.168.42.2:8090/api/v1/heartbeat".to_string(),
+            check_interval: Duration::from_secs(60), // Increased from 30 to 60 seconds to reduce CPU usage
+            idle_threshold: Duration::from_secs(5 * 60),
+            heartbeat_timeout: Duration::from_secs(5),
             wake_mode: WakeMode::default(),
+            server_cert_fingerprint: None,
+            server_name: None,
         }
     }
 }
@@ -91,21 +188,28 @@ pub fn generate_config() -> Result<()> {
 
     // Create YAML manually
     let yaml_content = format!(
-        r#"nas_mac: "{}"
+        r#"# Placeholders: run `nas-boot-client discover` to fill in the NAS addresses
+nas_mac: "{}"
 nas_ip: "{}"
 router_ip: "{}"
 heartbeat_url: "{}"
-check_interval_secs: {}
-idle_threshold_mins: {}
-heartbeat_timeout_secs: {}
+check_interval: "{}"
+idle_threshold: "{}"
+heartbeat_timeout: "{}"
+# Pin the certificate printed by `nas-boot-server generate-cert` when the
+# heartbeat_url uses https
+#server_cert_fingerprint: "AB:CD:..."
+# Follow the server advertised over mDNS under this name (by default its host
+# name) when its address changes
+#server_name: "nas"
 "#,
         default_config.nas_mac,
         default_config.nas_ip,
         default_config.router_ip,
         default_config.heartbeat_url,
-        default_config.check_interval_secs,
-        default_config.idle_threshold_mins,
-        default_config.heartbeat_timeout_secs
+        format_duration(default_config.check_interval),
+        format_duration(default_config.idle_threshold),
+        format_duration(default_config.heartbeat_timeout)
     );
 
     fs::write(&config_path, yaml_content)
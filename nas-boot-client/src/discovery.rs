@@ -0,0 +1,249 @@
+//! Finding the NAS Boot Server on the local network, instead of typing its
+//! addresses into the configuration
+
+use anyhow::{Context, Result};
+use nas_boot_protocol::{DiscoveryProbe, DiscoveryResponse, DISCOVERY_PORT, DISCOVERY_SERVICE};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant};
+
+use crate::config::{generate_config, get_config_path, load_config, save_config, Config};
+
+/// How long to wait for servers to answer a probe
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Send a probe to `target`, or broadcast it, and collect the answers of
+/// all servers, with the address each one answered from
+pub async fn discover(target: Option<IpAddr>) -> Result<Vec<(IpAddr, DiscoveryResponse)>> {
+    let target = target.unwrap_or(IpAddr::V4(Ipv4Addr::BROADCAST));
+    let local = match target {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    socket.set_broadcast(true)?;
+
+    let probe = serde_json::to_vec(&DiscoveryProbe::default())?;
+    socket
+        .send_to(&probe, SocketAddr::new(target, DISCOVERY_PORT))
+        .await
+        .with_context(|| format!("Failed to send discovery probe to {target}"))?;
+
+    let mut servers: Vec<(IpAddr, DiscoveryResponse)> = Vec::new();
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+    let mut buffer = vec![0; 64 * 1024];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+    {
+        let (length, source) = received?;
+        match serde_json::from_slice::<DiscoveryResponse>(&buffer[..length]) {
+            Ok(response) if response.service == DISCOVERY_SERVICE => {
+                // A server may answer the broadcast on several interfaces
+                if !servers
+                    .iter()
+                    .any(|(_, known)| known.server_id == response.server_id)
+                {
+                    servers.push((source.ip().to_canonical(), response));
+                }
+            }
+            _ => log::debug!("Ignoring unexpected datagram from {source}"),
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Find the server, or the one with `server_id` if several answer, and save
+/// its MAC address, IP address and heartbeat URL in the configuration. Its
+/// certificate is only pinned with `trust`.
+pub fn configure(server_id: Option<&str>, target: Option<IpAddr>, trust: bool) -> Result<()> {
+    let servers = tokio::runtime::Runtime::new()?.block_on(discover(target))?;
+    let candidates: Vec<_> = servers
+        .iter()
+        .filter(|(_, response)| server_id.is_none_or(|id| response.server_id == id))
+        .collect();
+
+    let (address, response) = match candidates.as_slice() {
+        [] if servers.is_empty() => {
+            return Err(anyhow::anyhow!(
+                "No NAS Boot Server answered. Is discovery enabled on the server and UDP port \
+                 {DISCOVERY_PORT} open?"
+            ))
+        }
+        [] => {
+            print_servers(&servers);
+            return Err(anyhow::anyhow!("None of the servers has that ID"));
+        }
+        [server] => *server,
+        _ => {
+            print_servers(&servers);
+            return Err(anyhow::anyhow!(
+                "Several servers answered, choose one with --server-id"
+            ));
+        }
+    };
+
+    if !get_config_path().exists() {
+        generate_config()?;
+    }
+    let mut config = load_config()?;
+    apply_response(&mut config, *address, response, trust)?;
+
+    println!(
+        "Found NAS Boot Server {} (version {}) at {address}",
+        response.server_id, response.server_version
+    );
+    println!("nas_mac: {}", config.nas_mac);
+    println!("nas_ip: {}", config.nas_ip);
+    println!("heartbeat_url: {}", config.heartbeat_url);
+    if let Some(fingerprint) = &config.server_cert_fingerprint {
+        println!("server_cert_fingerprint: {fingerprint}");
+    }
+
+    save_config(&config)
+}
+
+/// Point `config` at the server that answered from `address`. Anyone on the
+/// network can answer a probe, so a pinned certificate is never replaced or
+/// dropped, and a new one is only pinned with `trust`.
+fn apply_response(
+    config: &mut Config,
+    address: IpAddr,
+    response: &DiscoveryResponse,
+    trust: bool,
+) -> Result<()> {
+    let offered = response.cert_fingerprint.as_deref();
+    match (config.server_cert_fingerprint.as_deref(), offered) {
+        (Some(pinned), Some(offered)) if same_fingerprint(pinned, offered) => {}
+        (Some(pinned), _) => {
+            return Err(anyhow::anyhow!(
+            "Server {} presents {}, but {pinned} is pinned. Remove server_cert_fingerprint from \
+                 the configuration to switch servers",
+            response.server_id,
+            offered.map_or("no certificate".to_string(), |offered| format!(
+                "certificate {offered}"
+            ))
+        ))
+        }
+        (None, Some(offered)) if !trust => {
+            return Err(anyhow::anyhow!(
+            "Server {} presents certificate {offered}. Compare it with the fingerprint the server \
+                 logs when it starts, then run discover again with --trust to pin it",
+            response.server_id
+        ))
+        }
+        (None, Some(offered)) => config.server_cert_fingerprint = Some(offered.to_string()),
+        (None, None) => {}
+    }
+
+    match response.interface_with(address) {
+        Some(interface) => config.nas_mac.clone_from(&interface.mac),
+        None => log::warn!(
+            "Server {} didn't report an interface with {address}, keeping nas_mac {}",
+            response.server_id,
+            config.nas_mac
+        ),
+    }
+    config.nas_ip = address.to_string();
+    config.heartbeat_url = response.heartbeat_url(address);
+    Ok(())
+}
+
+/// Whether two fingerprints are the same, however they are written
+fn same_fingerprint(a: &str, b: &str) -> bool {
+    nas_boot_protocol::parse_fingerprint(a)
+        .is_some_and(|a| nas_boot_protocol::parse_fingerprint(b) == Some(a))
+}
+
+fn print_servers(servers: &[(IpAddr, DiscoveryResponse)]) {
+    for (address, response) in servers {
+        println!(
+            "{}  at {address}, version {}",
+            response.server_id, response.server_version
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nas_boot_protocol::{NetworkInterface, PROTOCOL_VERSION};
+
+    const PINNED: &str = "AB:4B:6F:D8:00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:00:11:22:33:44:55:66:77:88:99:FE:22";
+    const OTHER: &str = "01:4B:6F:D8:00:11:22:33:44:55:66:77:88:99:AA:BB:CC:DD:EE:FF:00:11:22:33:44:55:66:77:88:99:FE:22";
+
+    fn response(cert_fingerprint: Option<&str>) -> DiscoveryResponse {
+        DiscoveryResponse {
+            service: DISCOVERY_SERVICE.to_string(),
+            server_id: "server".to_string(),
+            server_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            api_port: 8090,
+            tls: cert_fingerprint.is_some(),
+            cert_fingerprint: cert_fingerprint.map(str::to_string),
+            interfaces: vec![NetworkInterface {
+                name: "eth0".to_string(),
+                mac: "00:11:22:33:44:55".to_string(),
+                addresses: vec![address()],
+            }],
+        }
+    }
+
+    fn address() -> IpAddr {
+        "192.168.1.10".parse().unwrap()
+    }
+
+    fn pinned_config() -> Config {
+        Config {
+            heartbeat_url: "https://192.168.1.2:8090/api/v1/heartbeat".to_string(),
+            server_cert_fingerprint: Some(PINNED.to_string()),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn keeps_the_pin_of_another_server() {
+        for offered in [Some(OTHER), None] {
+            let mut config = pinned_config();
+            assert!(apply_response(&mut config, address(), &response(offered), true).is_err());
+            assert_eq!(config.server_cert_fingerprint.as_deref(), Some(PINNED));
+            assert_eq!(config.heartbeat_url, pinned_config().heartbeat_url);
+            assert_eq!(config.nas_ip, pinned_config().nas_ip);
+        }
+    }
+
+    #[test]
+    fn follows_the_pinned_server() {
+        let mut config = pinned_config();
+        let offered = PINNED.to_lowercase().replace(':', "");
+        apply_response(&mut config, address(), &response(Some(&offered)), false).unwrap();
+        assert_eq!(config.server_cert_fingerprint.as_deref(), Some(PINNED));
+        assert_eq!(config.nas_ip, "192.168.1.10");
+        assert_eq!(config.nas_mac, "00:11:22:33:44:55");
+        assert_eq!(
+            config.heartbeat_url,
+            "https://192.168.1.10:8090/api/v1/heartbeat"
+        );
+    }
+
+    #[test]
+    fn pins_a_new_certificate_only_when_trusted() {
+        let mut config = Config::default();
+        assert!(apply_response(&mut config, address(), &response(Some(PINNED)), false).is_err());
+        assert_eq!(config.server_cert_fingerprint, None);
+        assert_eq!(config.nas_ip, Config::default().nas_ip);
+
+        apply_response(&mut config, address(), &response(Some(PINNED)), true).unwrap();
+        assert_eq!(config.server_cert_fingerprint.as_deref(), Some(PINNED));
+    }
+
+    #[test]
+    fn configures_a_plain_http_server() {
+        let mut config = Config::default();
+        apply_response(&mut config, address(), &response(None), false).unwrap();
+        assert_eq!(config.server_cert_fingerprint, None);
+        assert_eq!(
+            config.heartbeat_url,
+            "http://192.168.1.10:8090/api/v1/heartbeat"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use crate::app_state::AppState;
 use crate::config::{save_config, Config};
+use crate::mdns::follow_server;
 use crate::nas::send_heartbeat;
 use crate::system::{
     close_window, find_app_window, hide_window, is_auto_start_enabled, is_window_minimized,
@@ -361,6 +362,17 @@ pub fn run_gui_app(config: Config) -> Result<()> {
         std::thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
             rt.block_on(async {
+                // Follow the server's address, if it is resolved by name
+                {
+                    let config = config.clone();
+                    let cancel_token = cancel_token.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = follow_server(config, cancel_token).await {
+                            log::error!("Failed to resolve the server over mDNS: {e}");
+                        }
+                    });
+                }
+
                 // Start the main background task
                 let background_task = {
                     let cancel_token = cancel_token.clone();
@@ -445,7 +457,7 @@ pub async fn run_background_task(
     last_heartbeat: Arc<Mutex<Instant>>,
     cancel_token: tokio_util::sync::CancellationToken,
 ) -> Result<()> {
-    let mut interval = time::interval(Duration::from_secs(config.lock().check_interval_secs));
+    let mut interval = time::interval(config.lock().check_interval);
 
     // Create a channel for state change notifications
     let state_change_tx = Arc::new(tokio::sync::watch::channel(()).0);
@@ -464,7 +476,7 @@ pub async fn run_background_task(
         }
 
         let config = config.lock().clone();
-        let is_user_active = is_user_active(config.idle_threshold_mins);
+        let is_user_active = is_user_active(config.idle_threshold);
 
         // AppState Matrix:
         //
@@ -1,6 +1,7 @@
 #![windows_subsystem = "windows"]
 
 use std::io::Write;
+use std::net::IpAddr;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
@@ -10,8 +11,11 @@ use system::set_auto_start;
 
 mod app_state;
 mod config;
+mod discovery;
 mod gui;
+mod mdns;
 mod nas;
+mod pinning;
 mod system;
 mod user_activity;
 mod wake_mode;
@@ -40,6 +44,22 @@ enum Commands {
 
     /// Run the application with attached console
     WithConsole,
+
+    /// Find the NAS Boot Server on the network and save its addresses in the configuration
+    Discover {
+        /// The server to use when several answer
+        #[arg(long)]
+        server_id: Option<String>,
+
+        /// Send the probe to this address instead of broadcasting it
+        #[arg(long)]
+        address: Option<IpAddr>,
+
+        /// Pin the server's certificate, after comparing its fingerprint with
+        /// the one the server logs when it starts
+        #[arg(long)]
+        trust: bool,
+    },
 }
 
 fn main() -> Result<()> {
@@ -79,6 +99,14 @@ fn main() -> Result<()> {
         Some(Commands::DisableAutoStart) => set_auto_start(false).map(|()| {
             info!("Auto-start disabled");
         }),
+        Some(Commands::Discover {
+            server_id,
+            address,
+            trust,
+        }) => {
+            attach_console();
+            discovery::configure(server_id.as_deref(), address, trust)
+        }
         Some(Commands::WithConsole) => {
             attach_console();
             run_app()
@@ -0,0 +1,136 @@
+//! Resolving the server by name over mDNS / DNS-SD, so that the client keeps
+//! working when the NAS gets a new address
+
+use anyhow::Result;
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent};
+use nas_boot_protocol::{txt, MDNS_SERVICE_TYPE};
+use parking_lot::Mutex;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::config::Config;
+
+/// Follow the server named `server_name` in the configuration, if any, and
+/// update its addresses in `config` whenever it is resolved
+pub async fn follow_server(
+    config: Arc<Mutex<Config>>,
+    cancel_token: tokio_util::sync::CancellationToken,
+) -> Result<()> {
+    let Some(server_name) = config.lock().server_name.clone() else {
+        return Ok(());
+    };
+
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(MDNS_SERVICE_TYPE)?;
+    log::info!("Looking for server {server_name} over mDNS");
+
+    loop {
+        tokio::select! {
+            () = cancel_token.cancelled() => break,
+            event = events.recv_async() => match event {
+                Ok(ServiceEvent::ServiceResolved(service)) if is_named(&service, &server_name) => {
+                    update_config(&mut config.lock(), &service);
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            },
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(())
+}
+
+/// Whether `service` is the instance `name`, or the server with that ID
+fn is_named(service: &ResolvedService, name: &str) -> bool {
+    let instance = service
+        .fullname
+        .strip_suffix(MDNS_SERVICE_TYPE)
+        .and_then(|instance| instance.strip_suffix('.'))
+        .unwrap_or_default()
+        .replace("\\.", ".");
+    instance.eq_ignore_ascii_case(name)
+        || service.get_property_val_str(txt::SERVER_ID) == Some(name)
+}
+
+/// Point `config` at the address `service` resolved to
+fn update_config(config: &mut Config, service: &ResolvedService) {
+    let addresses: Vec<IpAddr> = service
+        .get_addresses()
+        .iter()
+        .map(|address| address.to_ip_addr())
+        .collect();
+
+    // Stay with the current address while it is still valid, otherwise
+    // prefer IPv4, which Wake-on-LAN and the shortcuts to the NAS rely on
+    let address = addresses
+        .iter()
+        .find(|address| address.to_string() == config.nas_ip)
+        .or_else(|| addresses.iter().filter(|address| address.is_ipv4()).min())
+        .or_else(|| {
+            addresses
+                .iter()
+                .filter(|address| !is_link_local(address))
+                .min()
+        });
+    let Some(address) = address else {
+        log::warn!(
+            "Server {} resolved without a usable address",
+            service.fullname
+        );
+        return;
+    };
+
+    let tls = service.get_property_val_str(txt::TLS) == Some("1");
+    if !tls && config.server_cert_fingerprint.is_some() {
+        // Following it would silently give up the pinned certificate
+        log::warn!(
+            "Server {} serves plain HTTP, but a server_cert_fingerprint is pinned; not following it",
+            service.fullname
+        );
+        return;
+    }
+    if tls && config.server_cert_fingerprint.is_none() {
+        log::warn!(
+            "Server {} serves HTTPS, but no server_cert_fingerprint is pinned",
+            service.fullname
+        );
+    }
+    let heartbeat_url = nas_boot_protocol::heartbeat_url(*address, service.port, tls);
+
+    let nas_ip = address.to_string();
+    if config.nas_ip != nas_ip || config.heartbeat_url != heartbeat_url {
+        log::info!("Server {} is now at {heartbeat_url}", service.fullname);
+        config.nas_ip = nas_ip;
+        config.heartbeat_url = heartbeat_url;
+    }
+    // Only the interface with the address followed is sure to wake the server
+    let mac = service
+        .get_property_val_str(txt::MAC)
+        .filter(|mac| !mac.is_empty());
+    let mac_addresses = service
+        .get_property_val_str(txt::MAC_ADDRESSES)
+        .unwrap_or_default();
+    let owns_address = mac_addresses
+        .split(',')
+        .filter_map(|mac_address| mac_address.parse::<IpAddr>().ok())
+        .any(|mac_address| mac_address == address.to_canonical());
+    if let Some(mac) = mac {
+        if !owns_address {
+            log::debug!(
+                "Server {} has MAC address {mac} on {mac_addresses}, not on {address}",
+                service.fullname
+            );
+        } else if !config.nas_mac.eq_ignore_ascii_case(mac) {
+            log::info!("Server {} has MAC address {mac}", service.fullname);
+            config.nas_mac = mac.to_string();
+        }
+    }
+}
+
+fn is_link_local(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => address.is_link_local(),
+        IpAddr::V6(address) => address.is_unicast_link_local(),
+    }
+}
@@ -1,28 +1,63 @@
 use anyhow::Result;
 use chrono::Local;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
+use nas_boot_protocol::{
+    Capability, ErrorResponse, HeartbeatRequest, HeartbeatResponse, PROTOCOL_VERSION,
+};
+use parking_lot::Mutex;
 use std::time::Duration;
 use tokio::time::timeout;
 
 use crate::config::Config;
+use crate::pinning;
 
-// Reuse HTTP client to avoid connection overhead
-static CLIENT: std::sync::OnceLock<reqwest::Client> = std::sync::OnceLock::new();
+// Reuse HTTP client to avoid connection overhead, until the pinned certificate changes
+static CLIENT: Mutex<Option<(Option<String>, reqwest::Client)>> = parking_lot::const_mutex(None);
 
-fn get_client() -> &'static reqwest::Client {
-    CLIENT.get_or_init(|| {
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(3)) // Shorter default timeout
-            .connect_timeout(Duration::from_secs(2)) // Connection timeout
-            .tcp_keepalive(Duration::from_secs(30))
-            .pool_idle_timeout(Duration::from_secs(90))
-            .build()
-            .unwrap_or_else(|_| reqwest::Client::new())
-    })
+fn get_client(config: &Config) -> Result<reqwest::Client> {
+    let mut cached = CLIENT.lock();
+    let pinned = &config.server_cert_fingerprint;
+    if let Some((fingerprint, client)) = cached.as_ref() {
+        if fingerprint == pinned {
+            return Ok(client.clone());
+        }
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3)) // Shorter default timeout
+        .connect_timeout(Duration::from_secs(2)) // Connection timeout
+        .tcp_keepalive(Duration::from_secs(30))
+        .pool_idle_timeout(Duration::from_secs(90));
+    if let Some(fingerprint) = pinned {
+        // A fingerprint that doesn't parse pins nothing, so no server is trusted
+        let fingerprint = nas_boot_protocol::parse_fingerprint(fingerprint).unwrap_or_default();
+        builder = builder.use_preconfigured_tls(pinning::client_config(fingerprint)?);
+    }
+
+    let client = match builder.build() {
+        Ok(client) => client,
+        // Falling back to a default client would drop the pinning
+        Err(e) if pinned.is_some() => return Err(e.into()),
+        Err(e) => {
+            error!("Failed to build HTTP client: {e}");
+            reqwest::Client::new()
+        }
+    };
+    *cached = Some((pinned.clone(), client.clone()));
+    Ok(client)
 }
 
+/// Optional protocol features implemented by this client
+const CLIENT_CAPABILITIES: &[Capability] = &[Capability::Leases];
+
 pub async fn send_heartbeat(config: &Config) -> Result<bool> {
-    let client = get_client();
+    let client = match get_client(config) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to set up the HTTP client: {e}");
+            return Ok(false);
+        }
+    };
     let timestamp = Local::now().to_rfc3339();
     let hostname = hostname::get()
         .unwrap_or_default()
@@ -32,23 +67,63 @@ pub async fn send_heartbeat(config: &Config) -> Result<bool> {
     info!("Sending heartbeat from {hostname}");
 
     // Add an additional timeout wrapper to prevent hanging
-    let heartbeat_future = client
-        .post(&config.heartbeat_url)
-        .json(&serde_json::json!({
-            "timestamp": timestamp,
-            "hostname": hostname
-        }))
-        .timeout(Duration::from_secs(config.heartbeat_timeout_secs))
-        .send();
+    let heartbeat_future = async {
+        let response = client
+            .post(&config.heartbeat_url)
+            .json(&HeartbeatRequest {
+                timestamp,
+                hostname,
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: CLIENT_CAPABILITIES.to_vec(),
+            })
+            .timeout(config.heartbeat_timeout)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+        Ok::<_, reqwest::Error>((status, body))
+    };
 
     // Wrap with tokio timeout for extra safety
-    match timeout(Duration::from_secs(config.heartbeat_timeout_secs + 1), heartbeat_future).await {
-        Ok(Ok(response)) => {
-            if response.status().is_success() {
-                info!("Heartbeat sent successfully to {}", config.heartbeat_url);
-                Ok(true)
+    let overall_timeout = config.heartbeat_timeout + Duration::from_secs(1);
+    match timeout(overall_timeout, heartbeat_future).await {
+        Ok(Ok((status, body))) => {
+            if status.is_success() {
+                // The legacy endpoint answers with a plain "OK" instead of JSON
+                match serde_json::from_str::<HeartbeatResponse>(&body) {
+                    Ok(response) if !response.accepted => {
+                        warn!("Heartbeat was not accepted by {}", config.heartbeat_url);
+                        Ok(false)
+                    }
+                    Ok(response) => {
+                        debug!(
+                            "Server {} speaks protocol version {}, shared capabilities {:?}",
+                            response.server_version,
+                            response.protocol_version,
+                            nas_boot_protocol::common_capabilities(
+                                CLIENT_CAPABILITIES,
+                                &response.capabilities
+                            )
+                        );
+                        info!(
+                            "Heartbeat accepted by {}, lease expires at {}",
+                            config.heartbeat_url, response.lease_expires_at
+                        );
+                        Ok(true)
+                    }
+                    Err(_) => {
+                        info!("Heartbeat sent successfully to {}", config.heartbeat_url);
+                        Ok(true)
+                    }
+                }
             } else {
-                error!("Heartbeat failed with status: {}", response.status());
+                match serde_json::from_str::<ErrorResponse>(&body) {
+                    Ok(response) => error!(
+                        "Heartbeat rejected with status {status}: {}",
+                        response.message
+                    ),
+                    Err(_) => error!("Heartbeat failed with status: {status}"),
+                }
                 Ok(false)
             }
         }
@@ -57,7 +132,7 @@ pub async fn send_heartbeat(config: &Config) -> Result<bool> {
             Ok(false) // Don't return error, just indicate failure
         }
         Err(_) => {
-            warn!("Heartbeat timed out after {}s", config.heartbeat_timeout_secs + 1);
+            warn!("Heartbeat timed out after {}", humantime::format_duration(overall_timeout));
             Ok(false) // Timeout - don't error, just indicate failure
         }
     }
@@ -0,0 +1,136 @@
+//! TLS configuration trusting exactly one server certificate, identified by
+//! its SHA-256 fingerprint, instead of the usual certificate authorities
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // The server name isn't checked: the pin identifies the server, whatever
+        // address it is reached at
+        let digest = ring::digest::digest(&ring::digest::SHA256, end_entity);
+        if digest.as_ref() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server certificate {} doesn't match the pinned fingerprint",
+                hex_fingerprint(digest.as_ref())
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn hex_fingerprint(digest: &[u8]) -> String {
+    let mut fingerprint = [0; 32];
+    fingerprint.copy_from_slice(digest);
+    nas_boot_protocol::format_fingerprint(&fingerprint)
+}
+
+/// Client TLS configuration that only accepts the certificate with `fingerprint`
+pub fn client_config(fingerprint: [u8; 32]) -> Result<ClientConfig, rustls::Error> {
+    let provider = Arc::new(crypto::ring::default_provider());
+
+    Ok(ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            fingerprint,
+            provider,
+        }))
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERTIFICATE: &[u8] = b"not really DER, but only its digest matters";
+
+    fn verify(fingerprint: [u8; 32]) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = PinnedCertificate {
+            fingerprint,
+            provider: Arc::new(crypto::ring::default_provider()),
+        };
+        verifier.verify_server_cert(
+            &CertificateDer::from(CERTIFICATE),
+            &[],
+            &ServerName::try_from("nas.local").unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    #[test]
+    fn accepts_the_pinned_certificate() {
+        let digest = ring::digest::digest(&ring::digest::SHA256, CERTIFICATE);
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(digest.as_ref());
+
+        assert!(verify(fingerprint).is_ok());
+    }
+
+    #[test]
+    fn rejects_any_other_certificate() {
+        let digest = ring::digest::digest(&ring::digest::SHA256, CERTIFICATE);
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(digest.as_ref());
+        fingerprint[0] ^= 0xff;
+
+        let error = verify(fingerprint).unwrap_err().to_string();
+        assert!(error.contains(&hex_fingerprint(digest.as_ref())), "{error}");
+        assert!(
+            error.contains("doesn't match the pinned fingerprint"),
+            "{error}"
+        );
+    }
+}
@@ -15,7 +15,7 @@ struct ActivityCache {
 
 static ACTIVITY_CACHE: OnceLock<Mutex<ActivityCache>> = OnceLock::new();
 
-pub fn is_user_active(idle_threshold_mins: u32) -> bool {
+pub fn is_user_active(idle_threshold: Duration) -> bool {
     let cache = ACTIVITY_CACHE.get_or_init(|| {
         Mutex::new(ActivityCache {
             last_check: Instant::now() - Duration::from_secs(60), // Force initial check
@@ -32,7 +32,7 @@ pub fn is_user_active(idle_threshold_mins: u32) -> bool {
     }
 
     // Calculate idle threshold in milliseconds
-    let idle_threshold_ms = u64::from(idle_threshold_mins) * 60 * 1000;
+    let idle_threshold_ms = idle_threshold.as_millis() as u64;
 
     // Get current tick count
     let current_tick_count = unsafe { GetTickCount() };
@@ -0,0 +1,383 @@
+//! Wire types exchanged between the NAS boot client and server.
+//!
+//! Every field added after the first release is optional on the wire, so
+//! older peers keep working: a missing `protocol_version` means a legacy peer
+//! (version 0) and a missing capability list means no optional features.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// Protocol version spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Protocol version assumed for peers that don't send one
+pub const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Optional protocol features a peer may support
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Heartbeat responses carry a lease expiry
+    Leases,
+    /// Clients can release their lease explicitly
+    Release,
+    /// Requests can be authenticated
+    Auth,
+    /// The server publishes power events
+    Events,
+    /// A capability introduced by a newer peer that this build doesn't know
+    #[serde(other)]
+    Unknown,
+}
+
+/// Capabilities supported by both sides, ignoring any unknown to this build
+pub fn common_capabilities(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    let mut common: Vec<Capability> = ours
+        .iter()
+        .filter(|capability| **capability != Capability::Unknown && theirs.contains(capability))
+        .copied()
+        .collect();
+    common.sort();
+    common.dedup();
+    common
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeartbeatRequest {
+    /// Client wall-clock time in RFC 3339 format
+    pub timestamp: String,
+    pub hostname: String,
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/// State of the server's shutdown timer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TimerState {
+    /// Clients are active, or the timer has not been started yet
+    #[default]
+    Stopped,
+    /// Still within the boot grace period, the timer cannot start yet
+    BootGrace,
+    /// Counting down towards a shutdown
+    Running { shutdown_at: DateTime<Utc> },
+    /// The delay has elapsed, but the shutdown is being held back
+    Held,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeartbeatResponse {
+    pub accepted: bool,
+    pub lease_expires_at: DateTime<Utc>,
+    pub timer: TimerState,
+    pub server_time: DateTime<Utc>,
+    pub server_version: String,
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/// JSON body of a rejected API request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub message: String,
+}
+
+/// Format a SHA-256 certificate fingerprint as colon-separated hex pairs,
+/// the form in which it is pinned in the client configuration
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Parse a SHA-256 fingerprint in hex, ignoring case and any colons
+pub fn parse_fingerprint(s: &str) -> Option<[u8; 32]> {
+    let hex: Vec<u8> = s.bytes().filter(|b| *b != b':').collect();
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut fingerprint = [0; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+/// UDP port on which servers answer discovery probes
+pub const DISCOVERY_PORT: u16 = 8091;
+
+/// Service name carried by discovery probes and responses, so that stray
+/// datagrams on the port are ignored
+pub const DISCOVERY_SERVICE: &str = "nas-boot";
+
+/// Broadcast by clients looking for servers on the local network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveryProbe {
+    pub service: String,
+    #[serde(default)]
+    pub protocol_version: u32,
+}
+
+impl Default for DiscoveryProbe {
+    fn default() -> Self {
+        Self {
+            service: DISCOVERY_SERVICE.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// A network interface of the server, to wake it through and reach it at
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub mac: String,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// A server's answer to a [`DiscoveryProbe`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveryResponse {
+    pub service: String,
+    /// Stays the same across restarts and address changes
+    pub server_id: String,
+    pub server_version: String,
+    #[serde(default)]
+    pub protocol_version: u32,
+    pub api_port: u16,
+    /// Whether the API is served over HTTPS
+    #[serde(default)]
+    pub tls: bool,
+    /// Fingerprint of the server certificate when `tls` is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_fingerprint: Option<String>,
+    pub interfaces: Vec<NetworkInterface>,
+}
+
+impl DiscoveryResponse {
+    /// The interface with `address`, e.g. the source of this response
+    pub fn interface_with(&self, address: IpAddr) -> Option<&NetworkInterface> {
+        let address = address.to_canonical();
+        self.interfaces
+            .iter()
+            .find(|interface| interface.addresses.contains(&address))
+    }
+
+    /// Heartbeat URL of the server when reached at `address`
+    pub fn heartbeat_url(&self, address: IpAddr) -> String {
+        heartbeat_url(address, self.api_port, self.tls)
+    }
+}
+
+/// Heartbeat URL of a server at `address` and `port`
+pub fn heartbeat_url(address: IpAddr, port: u16, tls: bool) -> String {
+    let scheme = if tls { "https" } else { "http" };
+    let server = SocketAddr::new(address.to_canonical(), port);
+    format!("{scheme}://{server}/api/v1/heartbeat")
+}
+
+/// DNS-SD service type under which servers advertise themselves over mDNS
+pub const MDNS_SERVICE_TYPE: &str = "_nas-boot._tcp.local.";
+
+/// Keys of the TXT record of the advertised service
+pub mod txt {
+    /// Version of the HTTP API, as in `/api/v1`
+    pub const API_VERSION: &str = "api_version";
+    pub const SERVER_ID: &str = "server_id";
+    /// MAC address to wake the server through
+    pub const MAC: &str = "mac";
+    /// Comma-separated IP addresses of the interface with [`MAC`]
+    pub const MAC_ADDRESSES: &str = "mac_addresses";
+    /// `1` if the API is served over HTTPS
+    pub const TLS: &str = "tls";
+}
+
+/// Version of the HTTP API served by this build
+pub const API_VERSION: u32 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn round_trip<T>(value: &T) -> T
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        serde_json::from_str(&serde_json::to_string(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn heartbeat_request_round_trips() {
+        let request = HeartbeatRequest {
+            timestamp: "2025-06-01T12:00:00+02:00".to_string(),
+            hostname: "workstation".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Leases, Capability::Release],
+        };
+
+        assert_eq!(round_trip(&request), request);
+    }
+
+    #[test]
+    fn heartbeat_response_round_trips() {
+        let server_time = Utc.with_ymd_and_hms(2025, 6, 1, 10, 0, 0).unwrap();
+        let response = HeartbeatResponse {
+            accepted: true,
+            lease_expires_at: server_time + chrono::Duration::minutes(2),
+            timer: TimerState::Running {
+                shutdown_at: server_time + chrono::Duration::minutes(10),
+            },
+            server_time,
+            server_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![Capability::Leases],
+        };
+
+        assert_eq!(round_trip(&response), response);
+    }
+
+    #[test]
+    fn legacy_heartbeat_request_is_accepted() {
+        let request: HeartbeatRequest = serde_json::from_str(
+            r#"{"timestamp": "2025-06-01T12:00:00+02:00", "hostname": "workstation"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(request.protocol_version, LEGACY_PROTOCOL_VERSION);
+        assert!(request.capabilities.is_empty());
+    }
+
+    #[test]
+    fn legacy_request_shape_is_preserved() {
+        let request = HeartbeatRequest {
+            timestamp: "2025-06-01T12:00:00+02:00".to_string(),
+            hostname: "workstation".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+        };
+
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["timestamp"], "2025-06-01T12:00:00+02:00");
+        assert_eq!(json["hostname"], "workstation");
+    }
+
+    #[test]
+    fn response_without_negotiation_fields_is_accepted() {
+        let response: HeartbeatResponse = serde_json::from_str(
+            r#"{
+                "accepted": true,
+                "lease_expires_at": "2025-06-01T10:02:00Z",
+                "timer": {"state": "stopped"},
+                "server_time": "2025-06-01T10:00:00Z",
+                "server_version": "0.1.0"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.protocol_version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(response.timer, TimerState::Stopped);
+        assert!(response.capabilities.is_empty());
+    }
+
+    #[test]
+    fn unknown_capabilities_degrade_gracefully() {
+        let request: HeartbeatRequest = serde_json::from_str(
+            r#"{
+                "timestamp": "2025-06-01T12:00:00+02:00",
+                "hostname": "workstation",
+                "protocol_version": 7,
+                "capabilities": ["leases", "telepathy"]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            request.capabilities,
+            vec![Capability::Leases, Capability::Unknown]
+        );
+        assert_eq!(
+            common_capabilities(
+                &[Capability::Leases, Capability::Events],
+                &request.capabilities
+            ),
+            vec![Capability::Leases]
+        );
+    }
+
+    #[test]
+    fn error_response_round_trips() {
+        let error = ErrorResponse {
+            error: "invalid_timestamp".to_string(),
+            message: "Invalid timestamp 'yesterday'".to_string(),
+        };
+
+        assert_eq!(round_trip(&error), error);
+    }
+
+    #[test]
+    fn fingerprint_round_trips() {
+        let fingerprint: [u8; 32] = std::array::from_fn(|i| (i * 8) as u8);
+        let formatted = format_fingerprint(&fingerprint);
+
+        assert_eq!(&formatted[..11], "00:08:10:18");
+        assert_eq!(parse_fingerprint(&formatted), Some(fingerprint));
+        assert_eq!(
+            parse_fingerprint(&formatted.replace(':', "").to_lowercase()),
+            Some(fingerprint)
+        );
+        assert_eq!(parse_fingerprint("00:08"), None);
+        assert_eq!(parse_fingerprint(&formatted.replace("08", "0G")), None);
+    }
+
+    #[test]
+    fn discovery_response_locates_the_server() {
+        let response = DiscoveryResponse {
+            service: DISCOVERY_SERVICE.to_string(),
+            server_id: "0123456789abcdef".to_string(),
+            server_version: "0.1.0".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            api_port: 8090,
+            tls: false,
+            cert_fingerprint: None,
+            interfaces: vec![
+                NetworkInterface {
+                    name: "eth0".to_string(),
+                    mac: "24:5E:BE:00:00:01".to_string(),
+                    addresses: vec!["192.168.1.10".parse().unwrap()],
+                },
+                NetworkInterface {
+                    name: "eth1".to_string(),
+                    mac: "24:5E:BE:00:00:02".to_string(),
+                    addresses: vec!["10.0.10.2".parse().unwrap(), "fd00::2".parse().unwrap()],
+                },
+            ],
+        };
+        assert_eq!(round_trip(&response), response);
+
+        // Responses from a dual-stack socket come from IPv4-mapped addresses
+        let source: IpAddr = "::ffff:10.0.10.2".parse().unwrap();
+        assert_eq!(response.interface_with(source).unwrap().name, "eth1");
+        assert_eq!(
+            response.heartbeat_url(source),
+            "http://10.0.10.2:8090/api/v1/heartbeat"
+        );
+        assert_eq!(
+            response.heartbeat_url("fd00::2".parse().unwrap()),
+            "http://[fd00::2]:8090/api/v1/heartbeat"
+        );
+        assert!(response
+            .interface_with("192.168.1.11".parse().unwrap())
+            .is_none());
+    }
+}
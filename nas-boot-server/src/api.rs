@@ -0,0 +1,372 @@
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Local, Utc};
+use log::{debug, error, info, warn};
+use nas_boot_protocol::{
+    Capability, ErrorResponse, HeartbeatRequest, HeartbeatResponse, TimerState,
+    LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::guard::PeerAddr;
+use crate::stats::StatsReport;
+use crate::{AppState, ClientInfo, MAX_TICK_INTERVAL};
+
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Optional protocol features implemented by this server
+const SERVER_CAPABILITIES: &[Capability] = &[Capability::Leases];
+
+#[derive(Serialize, Debug)]
+struct ClientStatus {
+    hostname: String,
+    last_seen_secs_ago: u64,
+    clock_skew_secs: Option<i64>,
+    protocol_version: u32,
+    capabilities: Vec<Capability>,
+}
+
+#[derive(Serialize, Debug)]
+struct ServerStatus {
+    server_time: DateTime<Utc>,
+    server_version: &'static str,
+    timer: TimerState,
+    /// Power tier entered last while no client was active
+    power_tier: Option<String>,
+    monitor: MonitorHealth,
+    clients: Vec<ClientStatus>,
+}
+
+/// Whether the shutdown monitor is still running
+#[derive(Serialize, Debug)]
+struct MonitorHealth {
+    /// The monitor ticked within twice its longest tick interval
+    alive: bool,
+    last_tick_secs_ago: Option<u64>,
+}
+
+impl MonitorHealth {
+    fn new(last_tick: Option<Instant>) -> Self {
+        let since = last_tick.map(|last_tick| Instant::now().saturating_duration_since(last_tick));
+        Self {
+            alive: since.is_some_and(|since| since <= 2 * MAX_TICK_INTERVAL),
+            last_tick_secs_ago: since.map(|since| since.as_secs()),
+        }
+    }
+}
+
+/// Error returned by the versioned API as a JSON body with a matching status code
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn invalid(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self {
+            status: rejection.status(),
+            code: "invalid_body",
+            message: rejection.body_text(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse {
+            error: self.code.to_string(),
+            message: self.message,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+fn routes() -> Router<AppState> {
+    let v1 = Router::new()
+        .route("/heartbeat", post(handle_heartbeat))
+        .route("/status", get(handle_status))
+        .route("/health", get(handle_health))
+        .route("/stats", get(handle_stats));
+
+    Router::new()
+        .route("/heartbeat", post(handle_legacy_heartbeat))
+        .nest("/api/v1", v1)
+}
+
+/// The API served on the network, behind the configured limits
+pub fn router(state: AppState) -> Router {
+    routes()
+        .layer(DefaultBodyLimit::max(state.config.http.max_body_bytes))
+        .layer(middleware::from_fn_with_state(state.clone(), guard_request))
+        .with_state(state)
+}
+
+/// The API served on the local admin socket, with operations not offered on the network
+pub fn admin_router(state: AppState) -> Router {
+    routes()
+        .route("/api/v1/clients/{hostname}", delete(handle_forget_client))
+        .with_state(state)
+}
+
+/// Enforce the per-peer rate limit and the request timeout, and log rejections
+async fn guard_request(
+    State(state): State<AppState>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let guard = &state.guard;
+    if !guard.rate_limiter.allow(peer.ip(), Instant::now()) {
+        guard.rejections.rejected("rate limit exceeded", peer.ip());
+        return ApiError {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            code: "rate_limited",
+            message: "Too many requests".to_string(),
+        }
+        .into_response();
+    }
+
+    let timeout = state.config.http.request_timeout;
+    let Ok(response) = tokio::time::timeout(timeout, next.run(request)).await else {
+        guard.rejections.rejected("request timed out", peer.ip());
+        return ApiError {
+            status: StatusCode::REQUEST_TIMEOUT,
+            code: "request_timeout",
+            message: format!(
+                "Request not completed within {}",
+                humantime::format_duration(timeout)
+            ),
+        }
+        .into_response();
+    };
+
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        guard
+            .rejections
+            .rejected("request body too large", peer.ip());
+    }
+    response
+}
+
+/// Heartbeat endpoint for clients predating the versioned API; answers "OK"
+/// unless the server tracks too many clients already
+async fn handle_legacy_heartbeat(
+    State(state): State<AppState>,
+    Json(heartbeat): Json<HeartbeatRequest>,
+) -> Result<&'static str, ApiError> {
+    let clock_skew = match DateTime::parse_from_rfc3339(&heartbeat.timestamp) {
+        Ok(dt) => Some(dt.with_timezone(&Utc).signed_duration_since(Utc::now())),
+        Err(e) => {
+            error!("Invalid timestamp: {e}");
+            None
+        }
+    };
+
+    record_heartbeat(&state, heartbeat, clock_skew).await?;
+
+    Ok("OK")
+}
+
+async fn handle_heartbeat(
+    State(state): State<AppState>,
+    heartbeat: Result<Json<HeartbeatRequest>, JsonRejection>,
+) -> Result<Json<HeartbeatResponse>, ApiError> {
+    let Json(heartbeat) = heartbeat?;
+
+    if heartbeat.hostname.trim().is_empty() {
+        return Err(ApiError::invalid(
+            "invalid_hostname",
+            "hostname must not be empty",
+        ));
+    }
+
+    let timestamp = DateTime::parse_from_rfc3339(&heartbeat.timestamp).map_err(|e| {
+        ApiError::invalid(
+            "invalid_timestamp",
+            format!("Invalid timestamp '{}': {e}", heartbeat.timestamp),
+        )
+    })?;
+
+    let server_time = Utc::now();
+    let clock_skew = timestamp
+        .with_timezone(&Utc)
+        .signed_duration_since(server_time);
+    record_heartbeat(&state, heartbeat, Some(clock_skew)).await?;
+
+    let lease = chrono::Duration::from_std(state.config.heartbeat_timeout).unwrap_or_default();
+
+    Ok(Json(HeartbeatResponse {
+        accepted: true,
+        lease_expires_at: server_time + lease,
+        timer: state.status.lock().await.timer,
+        server_time,
+        server_version: SERVER_VERSION.to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: SERVER_CAPABILITIES.to_vec(),
+    }))
+}
+
+/// Record a client as alive at the server's receive time.
+///
+/// The client's clock is only used to report how far off it is. Fails if
+/// the client is new and `max_clients` are tracked already.
+async fn record_heartbeat(
+    state: &AppState,
+    heartbeat: HeartbeatRequest,
+    clock_skew: Option<chrono::Duration>,
+) -> Result<(), ApiError> {
+    let received = Instant::now();
+    let mut clients = state.clients.lock().await;
+    let hostname = heartbeat.hostname;
+
+    let max_clients = state.config.http.max_clients;
+    if !clients.contains_key(&hostname) && clients.len() >= max_clients {
+        state
+            .guard
+            .rejections
+            .rejected("too many clients", format!("client {hostname}"));
+        return Err(ApiError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            code: "too_many_clients",
+            message: format!("The server already tracks {max_clients} clients"),
+        });
+    }
+
+    let known_version = clients.get(&hostname).map(|client| client.protocol_version);
+    if known_version != Some(heartbeat.protocol_version) {
+        if heartbeat.protocol_version == LEGACY_PROTOCOL_VERSION {
+            info!("Client {hostname} uses the legacy heartbeat protocol");
+        } else {
+            info!(
+                "Client {hostname} speaks protocol version {} with capabilities {:?}",
+                heartbeat.protocol_version,
+                nas_boot_protocol::common_capabilities(
+                    SERVER_CAPABILITIES,
+                    &heartbeat.capabilities
+                )
+            );
+        }
+    }
+
+    if let Some(skew) = clock_skew {
+        let threshold = state.config.clock_skew_warn;
+        let was_skewed = clients
+            .get(&hostname)
+            .and_then(|client| client.clock_skew)
+            .is_some_and(|previous| exceeds(previous, threshold));
+        if exceeds(skew, threshold) && !was_skewed {
+            warn!(
+                "Clock of client {hostname} is off by {}s",
+                skew.num_seconds()
+            );
+        }
+    }
+
+    debug!("Heartbeat from {hostname}");
+    let known = clients.insert(
+        hostname,
+        ClientInfo {
+            last_seen: received,
+            clock_skew,
+            protocol_version: heartbeat.protocol_version,
+            capabilities: heartbeat.capabilities,
+        },
+    );
+
+    // A new or returning client may cancel the shutdown timer
+    if known.is_none() {
+        state.wake.notify_one();
+    }
+
+    Ok(())
+}
+
+fn exceeds(skew: chrono::Duration, threshold: Duration) -> bool {
+    skew.abs().to_std().unwrap_or_default() > threshold
+}
+
+async fn handle_status(State(state): State<AppState>) -> Json<ServerStatus> {
+    let now = Instant::now();
+    let clients = state.clients.lock().await;
+
+    let mut clients: Vec<ClientStatus> = clients
+        .iter()
+        .map(|(hostname, client)| ClientStatus {
+            hostname: hostname.clone(),
+            last_seen_secs_ago: now.saturating_duration_since(client.last_seen).as_secs(),
+            clock_skew_secs: client.clock_skew.map(|skew| skew.num_seconds()),
+            protocol_version: client.protocol_version,
+            capabilities: client.capabilities.clone(),
+        })
+        .collect();
+    clients.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+
+    let status = state.status.lock().await.clone();
+    Json(ServerStatus {
+        server_time: Utc::now(),
+        server_version: SERVER_VERSION,
+        timer: status.timer,
+        power_tier: status.power_tier,
+        monitor: MonitorHealth::new(status.last_tick),
+        clients,
+    })
+}
+
+/// Liveness check for external supervisors: 503 if the monitor stopped ticking
+async fn handle_health(State(state): State<AppState>) -> Result<Json<MonitorHealth>, ApiError> {
+    let health = MonitorHealth::new(state.status.lock().await.last_tick);
+    if !health.alive {
+        return Err(ApiError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            code: "monitor_stalled",
+            message: match health.last_tick_secs_ago {
+                Some(secs) => format!("Shutdown monitor last ticked {secs}s ago"),
+                None => "Shutdown monitor has not ticked yet".to_string(),
+            },
+        });
+    }
+    Ok(Json(health))
+}
+
+/// Stop tracking a client, e.g. one that was retired without a final heartbeat
+async fn handle_forget_client(
+    State(state): State<AppState>,
+    Path(hostname): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if state.clients.lock().await.remove(&hostname).is_none() {
+        return Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            code: "unknown_client",
+            message: format!("No client {hostname} is tracked"),
+        });
+    }
+
+    info!("Forgot client {hostname}");
+    // The shutdown timer may start now
+    state.wake.notify_one();
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_stats(State(state): State<AppState>) -> Json<StatsReport> {
+    let stats = state.stats.lock().await;
+    Json(stats.report(state.config.energy_model(), Local::now(), None))
+}
@@ -0,0 +1,1049 @@
+use anyhow::{Context, Result};
+use humantime::format_duration;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::guard::Cidr;
+use crate::stats::EnergyModel;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const SECOND: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// Addresses to listen on; IPv6 ones also accept IPv4 unless an IPv4
+    /// address with the same port is listed as well
+    pub bind_addresses: Vec<SocketAddr>,
+    /// Unix socket serving the API to local tools, without the HTTP limits
+    pub admin_socket: Option<String>,
+    pub shutdown_delay: Duration,
+    pub keepalive_file: String,
+    pub backup_process_pattern: String,
+    pub heartbeat_timeout: Duration,
+    pub clock_skew_warn: Duration,
+    pub check_interval: Duration,
+    pub boot_grace_period: Duration,
+    pub min_uptime: Duration,
+    pub max_power_cycles_per_day: u32,
+    pub stats_file: String,
+    pub power_watts: f64,
+    pub energy_tariff_per_kwh: f64,
+    pub inhibitors: InhibitorsConfig,
+    pub ups: Option<UpsConfig>,
+    /// Power-saving steps taken one after another while no client is active;
+    /// empty means a shutdown after `shutdown_delay`
+    pub power_tiers: Vec<PowerTier>,
+    /// Tried in order until one powers the NAS off
+    pub shutdown_commands: Vec<String>,
+    /// Time for a shutdown command to take effect before the next one is tried
+    pub shutdown_verify_timeout: Duration,
+    /// Unprivileged user to run the network-facing server as, once it is
+    /// listening; root work is left to a helper process
+    pub user: Option<String>,
+    /// Unix socket on which the privileged helper listens
+    pub helper_socket: String,
+    pub http: HttpConfig,
+    /// Serve HTTPS instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+    pub discovery: DiscoveryConfig,
+    pub mdns: MdnsConfig,
+    /// Where the ID identifying this server to clients is kept
+    pub server_id_file: String,
+}
+
+/// Advertisement of the `_nas-boot._tcp` service over mDNS
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MdnsConfig {
+    pub enabled: bool,
+    /// Name clients resolve the service by; defaults to the host name
+    pub instance_name: Option<String>,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            instance_name: None,
+        }
+    }
+}
+
+/// UDP responder telling clients the server's addresses
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            port: nas_boot_protocol::DISCOVERY_PORT,
+        }
+    }
+}
+
+/// PEM files of the server certificate, e.g. made by `generate-cert`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            certificate: "/share/CACHEDEV1_DATA/.config/nas-boot/nas-boot-server.crt".to_string(),
+            private_key: "/share/CACHEDEV1_DATA/.config/nas-boot/nas-boot-server.key".to_string(),
+        }
+    }
+}
+
+/// Limits protecting the HTTP listener
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpConfig {
+    pub max_body_bytes: usize,
+    /// Requests per minute and peer address; 0 disables the limit
+    pub rate_limit_per_minute: u32,
+    /// Longest time to receive a request and answer it
+    pub request_timeout: Duration,
+    /// Connections without any traffic for this long are closed
+    pub idle_timeout: Duration,
+    /// Networks allowed to connect; empty allows everyone
+    pub allowed_clients: Vec<Cidr>,
+    /// Most clients tracked at a time; heartbeats from further ones are rejected
+    pub max_clients: usize,
+    /// Connections open at a time per peer address; 0 disables the limit
+    pub max_connections_per_peer: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 16 * 1024,
+            rate_limit_per_minute: 120,
+            request_timeout: 10 * SECOND,
+            idle_timeout: MINUTE,
+            allowed_clients: Vec::new(),
+            max_clients: 64,
+            max_connections_per_peer: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerTier {
+    pub name: String,
+    /// Time without active clients before the tier is entered
+    pub delay: Duration,
+    pub action: PowerAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum PowerAction {
+    /// Spin down disks with `hdparm -y`
+    DiskStandby {
+        disks: Vec<String>,
+    },
+    /// Stop a service through its init script, and start it again on undo
+    StopService {
+        service: String,
+    },
+    Command {
+        command: String,
+        undo_command: Option<String>,
+    },
+    Shutdown,
+}
+
+/// Connection to a NUT server (upsd) and how to react to power outages
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpsConfig {
+    pub host: String,
+    pub port: u16,
+    /// UPS name as configured in upsd, e.g. `qnapups`
+    pub name: String,
+    pub poll_interval: Duration,
+    /// Replaces `shutdown_delay` while on battery; zero skips the delay
+    pub on_battery_shutdown_delay: Duration,
+    /// Below this battery charge in percent, client heartbeats are ignored
+    pub min_battery_for_clients: Option<f64>,
+    /// Outages shorter than this neither arm the shutdown timer nor change the delay
+    pub outage_grace: Option<Duration>,
+}
+
+/// Optional inhibitors; each one is enabled by adding its section
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InhibitorsConfig {
+    pub cpu_load: Option<LoadInhibitorConfig>,
+    pub disk_io: Option<ThroughputInhibitorConfig>,
+    pub network: Option<ThroughputInhibitorConfig>,
+    pub login_sessions: Option<SessionInhibitorConfig>,
+    pub open_files: Option<OpenFilesInhibitorConfig>,
+    pub raid: Option<RaidInhibitorConfig>,
+    pub http: Vec<HttpInhibitorConfig>,
+    pub commands: Vec<CommandInhibitorConfig>,
+    pub plex: Option<ServiceInhibitorConfig>,
+    pub jellyfin: Option<ServiceInhibitorConfig>,
+    pub qbittorrent: Option<ServiceInhibitorConfig>,
+    pub transmission: Option<ServiceInhibitorConfig>,
+    pub containers: Option<ContainerInhibitorConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadInhibitorConfig {
+    /// One-minute load average to stay above
+    pub threshold: f64,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThroughputInhibitorConfig {
+    /// Disks or network interfaces to watch; empty selects all of them
+    pub devices: Vec<String>,
+    pub threshold_kib_per_sec: f64,
+    pub window: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInhibitorConfig {
+    pub utmp_file: String,
+    pub ignore_users: Vec<String>,
+    /// Only count sessions whose terminal saw input within this time
+    pub active_within: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenFilesInhibitorConfig {
+    pub shares: Vec<PathBuf>,
+    /// Process names, as in `/proc/<pid>/comm`, whose open files don't count
+    pub ignore_processes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaidInhibitorConfig {
+    pub mdstat_file: String,
+    /// Commands printing the state of a Btrfs or ZFS scrub, e.g. `zpool status`
+    pub scrub_commands: Vec<String>,
+    pub interval: Duration,
+    pub command_timeout: Duration,
+}
+
+/// Keeps the NAS on while a GET request meets the configured conditions;
+/// without any condition, a 2xx status is enough
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpInhibitorConfig {
+    pub name: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub status: Option<u16>,
+    /// JSON pointer into the response body, e.g. `/sessions/0/active`
+    pub json_pointer: Option<String>,
+    /// Value the pointer has to match; any truthy value if not set
+    pub equals: Option<serde_json::Value>,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Connection to a media server or torrent client with a built-in inhibitor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceInhibitorConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Torrents transferring slower than this don't count as active
+    pub min_rate_kib_per_sec: f64,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerInhibitorConfig {
+    /// Docker or Podman API socket
+    pub socket: PathBuf,
+    /// `key=value` labels; running containers carrying any of them keep the NAS on
+    pub labels: Vec<String>,
+    /// Containers that keep the NAS on while their health check reports `busy_health_status`
+    pub busy_containers: Vec<String>,
+    pub busy_health_status: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Keeps the NAS on while a script exits with status 0; its first output
+/// line becomes the reason
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandInhibitorConfig {
+    pub name: String,
+    pub command: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// The configured power tiers, or a single shutdown after `shutdown_delay`
+    pub fn effective_power_tiers(&self) -> Vec<PowerTier> {
+        if !self.power_tiers.is_empty() {
+            return self.power_tiers.clone();
+        }
+
+        vec![PowerTier {
+            name: "shutdown".to_string(),
+            delay: self.shutdown_delay,
+            action: PowerAction::Shutdown,
+        }]
+    }
+
+    pub fn energy_model(&self) -> EnergyModel {
+        EnergyModel {
+            power_watts: self.power_watts,
+            tariff_per_kwh: self.energy_tariff_per_kwh,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addresses: vec![SocketAddr::from(([0, 0, 0, 0], 8090))],
+            admin_socket: None,
+            shutdown_delay: 10 * MINUTE,
+            keepalive_file: "/share/Public/keepalive.txt".to_string(),
+            backup_process_pattern:
+                "python /share/CACHEDEV1_DATA/.qpkg/AzureStorage/bin/engine.pyc backup".to_string(),
+            heartbeat_timeout: 2 * MINUTE,
+            clock_skew_warn: MINUTE,
+            check_interval: 60 * SECOND,
+            boot_grace_period: 10 * MINUTE,
+            min_uptime: 30 * MINUTE,
+            max_power_cycles_per_day: 6,
+            stats_file: "/share/CACHEDEV1_DATA/.config/nas-boot/nas-boot-server-stats.json"
+                .to_string(),
+            power_watts: 30.0,
+            energy_tariff_per_kwh: 0.30,
+            inhibitors: InhibitorsConfig::default(),
+            ups: None,
+            power_tiers: Vec::new(),
+            shutdown_commands: vec![
+                "/sbin/poweroff".to_string(),
+                "/sbin/halt -p".to_string(),
+                "systemctl poweroff".to_string(),
+            ],
+            shutdown_verify_timeout: 3 * MINUTE,
+            user: None,
+            helper_socket: "/var/run/nas-boot-server.sock".to_string(),
+            http: HttpConfig::default(),
+            tls: None,
+            discovery: DiscoveryConfig::default(),
+            mdns: MdnsConfig::default(),
+            server_id_file: "/share/CACHEDEV1_DATA/.config/nas-boot/nas-boot-server-id".to_string(),
+        }
+    }
+}
+
+pub fn get_config_path() -> PathBuf {
+    PathBuf::from("/share/CACHEDEV1_DATA/.config/nas-boot/nas-boot-server-config.yaml")
+}
+
+pub fn load_config() -> Result<Config> {
+    load_config_from(&get_config_path())
+}
+
+pub fn load_config_from(config_path: &Path) -> Result<Config> {
+    if !config_path.exists() {
+        return Err(anyhow::anyhow!(
+            "Configuration file not found at: {}. Run with 'generate-config' to create it.",
+            config_path.display()
+        ));
+    }
+
+    let config_str = fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read config from {}", config_path.display()))?;
+
+    let docs = YamlLoader::load_from_str(&config_str).context("Failed to parse YAML")?;
+
+    if docs.is_empty() {
+        return Err(anyhow::anyhow!("Empty configuration file"));
+    }
+
+    let doc = &docs[0];
+    let defaults = Config::default();
+
+    let config = Config {
+        bind_addresses: parse_bind_addresses(doc)?,
+        admin_socket: doc["admin_socket"].as_str().map(ToString::to_string),
+        shutdown_delay: yaml_legacy_duration(doc, "shutdown_delay", "shutdown_delay_mins", MINUTE)?
+            .ok_or_else(|| anyhow::anyhow!("Missing shutdown_delay"))?,
+        keepalive_file: doc["keepalive_file"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing keepalive_file"))?
+            .to_string(),
+        backup_process_pattern: doc["backup_process_pattern"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing backup_process_pattern"))?
+            .to_string(),
+        heartbeat_timeout: yaml_legacy_duration(
+            doc,
+            "heartbeat_timeout",
+            "heartbeat_timeout_mins",
+            MINUTE,
+        )?
+        .ok_or_else(|| anyhow::anyhow!("Missing heartbeat_timeout"))?,
+        clock_skew_warn: yaml_duration(doc, "clock_skew_warn")?.unwrap_or(defaults.clock_skew_warn),
+        check_interval: yaml_legacy_duration(doc, "check_interval", "check_interval_secs", SECOND)?
+            .ok_or_else(|| anyhow::anyhow!("Missing check_interval"))?,
+        boot_grace_period: yaml_legacy_duration(
+            doc,
+            "boot_grace_period",
+            "boot_grace_period_mins",
+            MINUTE,
+        )?
+        .unwrap_or(defaults.boot_grace_period),
+        min_uptime: yaml_legacy_duration(doc, "min_uptime", "min_uptime_mins", MINUTE)?
+            .unwrap_or(defaults.min_uptime),
+        max_power_cycles_per_day: yaml_integer(doc, "max_power_cycles_per_day")?
+            .unwrap_or(defaults.max_power_cycles_per_day),
+        stats_file: doc["stats_file"]
+            .as_str()
+            .map_or(defaults.stats_file, ToString::to_string),
+        power_watts: yaml_f64(&doc["power_watts"]).unwrap_or(defaults.power_watts),
+        energy_tariff_per_kwh: yaml_f64(&doc["energy_tariff_per_kwh"])
+            .unwrap_or(defaults.energy_tariff_per_kwh),
+        inhibitors: parse_inhibitors(&doc["inhibitors"])?,
+        ups: parse_ups(doc)?,
+        power_tiers: parse_power_tiers(doc)?,
+        shutdown_commands: match yaml_strings(&doc["shutdown_commands"]) {
+            commands if commands.is_empty() => defaults.shutdown_commands,
+            commands => commands,
+        },
+        shutdown_verify_timeout: yaml_duration(doc, "shutdown_verify_timeout")?
+            .unwrap_or(defaults.shutdown_verify_timeout),
+        user: doc["user"].as_str().map(ToString::to_string),
+        helper_socket: doc["helper_socket"]
+            .as_str()
+            .map_or(defaults.helper_socket, ToString::to_string),
+        http: parse_http(&doc["http"])?,
+        tls: yaml_section(doc, "tls").map(|tls| {
+            let defaults = TlsConfig::default();
+            TlsConfig {
+                certificate: tls["certificate"]
+                    .as_str()
+                    .map_or(defaults.certificate, ToString::to_string),
+                private_key: tls["private_key"]
+                    .as_str()
+                    .map_or(defaults.private_key, ToString::to_string),
+            }
+        }),
+        discovery: {
+            let defaults = DiscoveryConfig::default();
+            let section = &doc["discovery"];
+            DiscoveryConfig {
+                enabled: section["enabled"].as_bool().unwrap_or(defaults.enabled),
+                port: match section["port"].as_i64() {
+                    Some(port) => u16::try_from(port)
+                        .with_context(|| format!("Invalid discovery.port: {port}"))?,
+                    None => defaults.port,
+                },
+            }
+        },
+        mdns: MdnsConfig {
+            enabled: doc["mdns"]["enabled"]
+                .as_bool()
+                .unwrap_or(defaults.mdns.enabled),
+            instance_name: doc["mdns"]["instance_name"]
+                .as_str()
+                .map(ToString::to_string),
+        },
+        server_id_file: doc["server_id_file"]
+            .as_str()
+            .map_or(defaults.server_id_file, ToString::to_string),
+    };
+
+    if config.check_interval.is_zero() {
+        return Err(anyhow::anyhow!("check_interval must be greater than zero"));
+    }
+    if config
+        .ups
+        .as_ref()
+        .is_some_and(|ups| ups.poll_interval.is_zero())
+    {
+        return Err(anyhow::anyhow!(
+            "ups.poll_interval must be greater than zero"
+        ));
+    }
+
+    Ok(config)
+}
+
+fn parse_bind_addresses(doc: &Yaml) -> Result<Vec<SocketAddr>> {
+    let addresses = yaml_strings(&doc["bind_address"]);
+    if addresses.is_empty() {
+        return Err(anyhow::anyhow!("Missing bind_address"));
+    }
+
+    addresses
+        .iter()
+        .map(|address| {
+            address
+                .parse()
+                .with_context(|| format!("Invalid bind_address '{address}', expected IP:port"))
+        })
+        .collect()
+}
+
+fn parse_http(section: &Yaml) -> Result<HttpConfig> {
+    let defaults = HttpConfig::default();
+
+    let allowed_clients = yaml_strings(&section["allowed_clients"])
+        .iter()
+        .map(|cidr| cidr.parse().context("Invalid http.allowed_clients"))
+        .collect::<Result<_>>()?;
+
+    Ok(HttpConfig {
+        max_body_bytes: yaml_integer(section, "max_body_bytes")?.unwrap_or(defaults.max_body_bytes),
+        rate_limit_per_minute: yaml_integer(section, "rate_limit_per_minute")?
+            .unwrap_or(defaults.rate_limit_per_minute),
+        request_timeout: yaml_duration(section, "request_timeout")?
+            .unwrap_or(defaults.request_timeout),
+        idle_timeout: yaml_duration(section, "idle_timeout")?.unwrap_or(defaults.idle_timeout),
+        allowed_clients,
+        max_clients: yaml_integer(section, "max_clients")?.unwrap_or(defaults.max_clients),
+        max_connections_per_peer: yaml_integer(section, "max_connections_per_peer")?
+            .unwrap_or(defaults.max_connections_per_peer),
+    })
+}
+
+fn parse_power_tiers(doc: &Yaml) -> Result<Vec<PowerTier>> {
+    let mut tiers: Vec<PowerTier> = Vec::new();
+
+    for (index, tier) in yaml_list(doc, "power_tiers").iter().enumerate() {
+        let name = tier["name"]
+            .as_str()
+            .map(ToString::to_string)
+            .ok_or_else(|| anyhow::anyhow!("Missing name for power tier {}", index + 1))?;
+        // The helper and the status refer to tiers by name
+        if tiers.iter().any(|tier| tier.name == name) {
+            return Err(anyhow::anyhow!("Duplicate power tier name {name}"));
+        }
+        let delay = yaml_duration(tier, "delay")?
+            .ok_or_else(|| anyhow::anyhow!("Missing delay for power tier {name}"))?;
+        let required = |key: &str| {
+            tier[key]
+                .as_str()
+                .map(ToString::to_string)
+                .ok_or_else(|| anyhow::anyhow!("Missing {key} for power tier {name}"))
+        };
+
+        let action = match tier["action"].as_str() {
+            Some("disk_standby") => PowerAction::DiskStandby {
+                disks: yaml_strings(&tier["disks"]),
+            },
+            Some("stop_service") => PowerAction::StopService {
+                service: required("service")?,
+            },
+            Some("command") => PowerAction::Command {
+                command: required("command")?,
+                undo_command: tier["undo_command"].as_str().map(ToString::to_string),
+            },
+            Some("shutdown") => PowerAction::Shutdown,
+            Some(other) => {
+                return Err(anyhow::anyhow!(
+                    "Unknown action '{other}' for power tier {name}"
+                ))
+            }
+            None => return Err(anyhow::anyhow!("Missing action for power tier {name}")),
+        };
+
+        if let Some(previous) = tiers.last() {
+            if previous.action == PowerAction::Shutdown {
+                return Err(anyhow::anyhow!(
+                    "The shutdown power tier must be the last one"
+                ));
+            }
+            if delay < previous.delay {
+                return Err(anyhow::anyhow!(
+                    "Power tier {name} must not have a shorter delay than {}",
+                    previous.name
+                ));
+            }
+        }
+
+        tiers.push(PowerTier {
+            name,
+            delay,
+            action,
+        });
+    }
+
+    Ok(tiers)
+}
+
+fn parse_ups(doc: &Yaml) -> Result<Option<UpsConfig>> {
+    let Some(ups) = yaml_section(doc, "ups") else {
+        return Ok(None);
+    };
+
+    let port = match ups["port"].as_i64() {
+        Some(port) => u16::try_from(port).with_context(|| format!("Invalid ups.port: {port}"))?,
+        None => 3493,
+    };
+
+    Ok(Some(UpsConfig {
+        host: ups["host"].as_str().unwrap_or("127.0.0.1").to_string(),
+        port,
+        name: ups["name"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing ups.name"))?
+            .to_string(),
+        poll_interval: yaml_duration(ups, "poll_interval")?.unwrap_or(10 * SECOND),
+        on_battery_shutdown_delay: yaml_duration(ups, "on_battery_shutdown_delay")?
+            .unwrap_or(Duration::ZERO),
+        min_battery_for_clients: yaml_f64(&ups["min_battery_for_clients"]),
+        outage_grace: yaml_duration(ups, "outage_grace")?,
+    }))
+}
+
+const DEFAULT_INHIBITOR_WINDOW: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_MIN_TORRENT_RATE: f64 = 50.0;
+
+fn parse_inhibitors(section: &Yaml) -> Result<InhibitorsConfig> {
+    let mut inhibitors = InhibitorsConfig::default();
+
+    if let Some(load) = yaml_section(section, "cpu_load") {
+        inhibitors.cpu_load = Some(LoadInhibitorConfig {
+            threshold: yaml_f64(&load["threshold"])
+                .ok_or_else(|| anyhow::anyhow!("Missing inhibitors.cpu_load.threshold"))?,
+            window: yaml_duration(load, "window")?.unwrap_or(DEFAULT_INHIBITOR_WINDOW),
+        });
+    }
+
+    if let Some(disk) = yaml_section(section, "disk_io") {
+        inhibitors.disk_io = Some(parse_throughput(disk, "disk_io", "devices")?);
+    }
+
+    if let Some(network) = yaml_section(section, "network") {
+        inhibitors.network = Some(parse_throughput(network, "network", "interfaces")?);
+    }
+
+    if let Some(sessions) = yaml_section(section, "login_sessions") {
+        inhibitors.login_sessions = Some(SessionInhibitorConfig {
+            utmp_file: sessions["utmp_file"]
+                .as_str()
+                .unwrap_or("/var/run/utmp")
+                .to_string(),
+            ignore_users: yaml_strings(&sessions["ignore_users"]),
+            active_within: yaml_duration(sessions, "active_within")?,
+        });
+    }
+
+    if let Some(open_files) = yaml_section(section, "open_files") {
+        let shares: Vec<PathBuf> = yaml_strings(&open_files["shares"])
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        if shares.is_empty() {
+            return Err(anyhow::anyhow!("Missing inhibitors.open_files.shares"));
+        }
+        inhibitors.open_files = Some(OpenFilesInhibitorConfig {
+            shares,
+            ignore_processes: yaml_strings(&open_files["ignore_processes"]),
+        });
+    }
+
+    if let Some(raid) = yaml_section(section, "raid") {
+        inhibitors.raid = Some(RaidInhibitorConfig {
+            mdstat_file: raid["mdstat_file"]
+                .as_str()
+                .unwrap_or("/proc/mdstat")
+                .to_string(),
+            scrub_commands: yaml_strings(&raid["scrub_commands"]),
+            interval: yaml_duration(raid, "interval")?.unwrap_or(DEFAULT_PROBE_INTERVAL),
+            command_timeout: yaml_duration(raid, "command_timeout")?
+                .unwrap_or(DEFAULT_COMMAND_TIMEOUT),
+        });
+    }
+
+    for (index, http) in yaml_list(section, "http").iter().enumerate() {
+        let name = yaml_probe_name(http, "http", index)?;
+        inhibitors.http.push(HttpInhibitorConfig {
+            url: http["url"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Missing url for HTTP inhibitor {name}"))?
+                .to_string(),
+            headers: yaml_string_map(&http["headers"]),
+            status: yaml_integer(http, "status")?,
+            json_pointer: http["json_pointer"].as_str().map(ToString::to_string),
+            equals: yaml_scalar_to_json(&http["equals"]),
+            interval: yaml_duration(http, "interval")?.unwrap_or(DEFAULT_PROBE_INTERVAL),
+            timeout: yaml_duration(http, "timeout")?.unwrap_or(DEFAULT_COMMAND_TIMEOUT),
+            name,
+        });
+    }
+
+    for (index, command) in yaml_list(section, "commands").iter().enumerate() {
+        let name = yaml_probe_name(command, "commands", index)?;
+        inhibitors.commands.push(CommandInhibitorConfig {
+            command: command["command"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Missing command for command inhibitor {name}"))?
+                .to_string(),
+            interval: yaml_duration(command, "interval")?.unwrap_or(DEFAULT_PROBE_INTERVAL),
+            timeout: yaml_duration(command, "timeout")?.unwrap_or(DEFAULT_COMMAND_TIMEOUT),
+            name,
+        });
+    }
+
+    inhibitors.plex = parse_service(section, "plex")?;
+    inhibitors.jellyfin = parse_service(section, "jellyfin")?;
+    inhibitors.qbittorrent = parse_service(section, "qbittorrent")?;
+    inhibitors.transmission = parse_service(section, "transmission")?;
+
+    if let Some(containers) = yaml_section(section, "containers") {
+        inhibitors.containers = Some(ContainerInhibitorConfig {
+            socket: PathBuf::from(
+                containers["socket"]
+                    .as_str()
+                    .unwrap_or("/var/run/docker.sock"),
+            ),
+            labels: yaml_strings(&containers["labels"]),
+            busy_containers: yaml_strings(&containers["busy_containers"]),
+            busy_health_status: containers["busy_health_status"]
+                .as_str()
+                .unwrap_or("healthy")
+                .to_string(),
+            interval: yaml_duration(containers, "interval")?.unwrap_or(DEFAULT_PROBE_INTERVAL),
+            timeout: yaml_duration(containers, "timeout")?.unwrap_or(DEFAULT_COMMAND_TIMEOUT),
+        });
+    }
+
+    Ok(inhibitors)
+}
+
+fn parse_service(section: &Yaml, name: &str) -> Result<Option<ServiceInhibitorConfig>> {
+    let Some(service) = yaml_section(section, name) else {
+        return Ok(None);
+    };
+    let optional = |key: &str| service[key].as_str().map(ToString::to_string);
+
+    Ok(Some(ServiceInhibitorConfig {
+        url: optional("url").ok_or_else(|| anyhow::anyhow!("Missing inhibitors.{name}.url"))?,
+        api_key: optional("api_key"),
+        username: optional("username"),
+        password: optional("password"),
+        min_rate_kib_per_sec: yaml_f64(&service["min_rate_kib_per_sec"])
+            .unwrap_or(DEFAULT_MIN_TORRENT_RATE),
+        interval: yaml_duration(service, "interval")?.unwrap_or(DEFAULT_PROBE_INTERVAL),
+        timeout: yaml_duration(service, "timeout")?.unwrap_or(DEFAULT_COMMAND_TIMEOUT),
+    }))
+}
+
+fn yaml_list<'a>(doc: &'a Yaml, key: &str) -> &'a [Yaml] {
+    doc[key].as_vec().map_or(&[], Vec::as_slice)
+}
+
+fn yaml_probe_name(probe: &Yaml, list: &str, index: usize) -> Result<String> {
+    probe["name"]
+        .as_str()
+        .map(ToString::to_string)
+        .ok_or_else(|| anyhow::anyhow!("Missing name for entry {} of inhibitors.{list}", index + 1))
+}
+
+fn yaml_string_map(value: &Yaml) -> Vec<(String, String)> {
+    value
+        .as_hash()
+        .map(|hash| {
+            hash.iter()
+                .filter_map(|(key, value)| {
+                    let value = match value {
+                        Yaml::String(value) => value.clone(),
+                        Yaml::Integer(value) => value.to_string(),
+                        _ => return None,
+                    };
+                    Some((key.as_str()?.to_string(), value))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn yaml_scalar_to_json(value: &Yaml) -> Option<serde_json::Value> {
+    match value {
+        Yaml::String(value) => Some(serde_json::Value::from(value.as_str())),
+        Yaml::Integer(value) => Some(serde_json::Value::from(*value)),
+        Yaml::Real(_) => yaml_f64(value).map(serde_json::Value::from),
+        Yaml::Boolean(value) => Some(serde_json::Value::from(*value)),
+        _ => None,
+    }
+}
+
+fn parse_throughput(
+    section: &Yaml,
+    name: &str,
+    devices_key: &str,
+) -> Result<ThroughputInhibitorConfig> {
+    Ok(ThroughputInhibitorConfig {
+        devices: yaml_strings(&section[devices_key]),
+        threshold_kib_per_sec: yaml_f64(&section["threshold_kib_per_sec"])
+            .ok_or_else(|| anyhow::anyhow!("Missing inhibitors.{name}.threshold_kib_per_sec"))?,
+        window: yaml_duration(section, "window")?.unwrap_or(DEFAULT_INHIBITOR_WINDOW),
+    })
+}
+
+/// The section at `key`, if present
+fn yaml_section<'a>(doc: &'a Yaml, key: &str) -> Option<&'a Yaml> {
+    let value = &doc[key];
+    (!value.is_badvalue()).then_some(value)
+}
+
+fn yaml_strings(value: &Yaml) -> Vec<String> {
+    match value {
+        Yaml::Array(items) => items
+            .iter()
+            .filter_map(|item| item.as_str().map(ToString::to_string))
+            .collect(),
+        Yaml::String(item) => vec![item.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// Read a human-readable duration ("90s", "10m", "1h30m") from `key`
+fn yaml_duration(doc: &Yaml, key: &str) -> Result<Option<Duration>> {
+    match &doc[key] {
+        Yaml::String(value) => humantime::parse_duration(value)
+            .map(Some)
+            .with_context(|| format!("Invalid duration for {key}: {value}")),
+        Yaml::BadValue | Yaml::Null => Ok(None),
+        // Rather than guessing the unit, or ignoring the value
+        Yaml::Integer(value) => Err(anyhow::anyhow!(
+            "Invalid duration for {key}: {value} lacks a unit, e.g. \"{value}s\""
+        )),
+        _ => Err(anyhow::anyhow!(
+            "Invalid duration for {key}: expected a string such as \"60s\""
+        )),
+    }
+}
+
+/// Like [`yaml_duration`], but falls back to the legacy integer `legacy_key`
+/// counted in `legacy_unit`
+fn yaml_legacy_duration(
+    doc: &Yaml,
+    key: &str,
+    legacy_key: &str,
+    legacy_unit: Duration,
+) -> Result<Option<Duration>> {
+    if let Some(duration) = yaml_duration(doc, key)? {
+        return Ok(Some(duration));
+    }
+
+    if let Some(value) = doc[legacy_key].as_i64() {
+        let count = u32::try_from(value)
+            .with_context(|| format!("Invalid value for {legacy_key}: {value}"))?;
+        return Ok(Some(legacy_unit * count));
+    }
+
+    Ok(None)
+}
+
+/// Read an integer from `key` that fits in `T`, e.g. isn't negative for a count
+fn yaml_integer<T: TryFrom<i64>>(doc: &Yaml, key: &str) -> Result<Option<T>> {
+    doc[key]
+        .as_i64()
+        .map(|value| {
+            T::try_from(value).map_err(|_| anyhow::anyhow!("Invalid value for {key}: {value}"))
+        })
+        .transpose()
+}
+
+// YAML distinguishes integers from reals, but `power_watts: 30` should work too
+fn yaml_f64(value: &Yaml) -> Option<f64> {
+    value.as_f64().or_else(|| value.as_i64().map(|v| v as f64))
+}
+
+pub fn generate_config() -> Result<()> {
+    let config_path = get_config_path();
+
+    if let Some(parent) = config_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+
+    let default_config = Config::default();
+
+    // Create YAML manually
+    let yaml_content = format!(
+        r#"# One address or a list, e.g. ["192.168.42.2:8090", "[::]:8090"]
+bind_address: "{}"
+# Local tools can use the API through this socket without the limits below
+admin_socket: "/var/run/nas-boot-server-admin.sock"
+shutdown_delay: "{}"
+keepalive_file: "{}"
+backup_process_pattern: "{}"
+heartbeat_timeout: "{}"
+clock_skew_warn: "{}"
+check_interval: "{}"
+boot_grace_period: "{}"
+min_uptime: "{}"
+max_power_cycles_per_day: {}
+stats_file: "{}"
+power_watts: {:.1}
+energy_tariff_per_kwh: {:.2}
+
+# Tried in order while the NAS is still up shutdown_verify_timeout after the last one
+shutdown_commands:
+{}
+shutdown_verify_timeout: "{}"
+
+# Run the network-facing server as this user once it is listening; a helper
+# process keeps running as root for the shutdown and other privileged work
+#user: "nasboot"
+#helper_socket: "{}"
+
+# Limits protecting the HTTP listener
+http:
+  max_body_bytes: {}
+  rate_limit_per_minute: {}  # per peer address, 0 disables the limit
+  request_timeout: "{}"
+  idle_timeout: "{}"
+  allowed_clients: []        # e.g. ["192.168.42.0/24", "fd00::/64"]; empty allows all
+  max_clients: {}
+  max_connections_per_peer: {}  # 0 disables the limit
+
+# Serve HTTPS with a certificate made by `nas-boot-server generate-cert`
+#tls:
+#  certificate: "{}"
+#  private_key: "{}"
+
+# Answer clients looking for the server with `nas-boot-client discover`
+discovery:
+  enabled: {}
+  port: {}
+#server_id_file: "{}"
+
+# Advertise the _nas-boot._tcp service over mDNS, for clients with a server_name
+mdns:
+  enabled: {}
+#  instance_name: "nas"      # defaults to the host name
+
+# Optional power-saving steps before the shutdown; replaces shutdown_delay
+#power_tiers:
+#  - name: "spin_down"
+#    delay: "15m"
+#    action: "disk_standby"   # or stop_service, command, shutdown
+#    disks: ["/dev/sda", "/dev/sdb"]
+#  - name: "power_off"
+#    delay: "2h"
+#    action: "shutdown"
+
+# Optional NUT server to learn about power outages from
+#ups:
+#  host: "127.0.0.1"
+#  port: 3493
+#  name: "qnapups"
+#  on_battery_shutdown_delay: "0s"
+#  min_battery_for_clients: 50
+#  outage_grace: "1m"
+
+# Optional inhibitors that keep the NAS on while it is busy by itself.
+# Uncomment a section to enable it.
+#inhibitors:
+#  cpu_load:
+#    threshold: 2.0
+#    window: "5m"
+#  disk_io:
+#    devices: []              # e.g. ["sda", "sdb"]; empty watches all disks
+#    threshold_kib_per_sec: 1024
+#    window: "5m"
+#  network:
+#    interfaces: []           # e.g. ["eth0"]; empty watches all but loopback
+#    threshold_kib_per_sec: 512
+#    window: "5m"
+#  login_sessions:
+#    ignore_users: []         # e.g. ["backup"]
+#    active_within: "30m"     # omit to count idle sessions too
+#  open_files:
+#    shares: ["/share/CACHEDEV1_DATA/Multimedia"]
+#    ignore_processes: []     # e.g. ["qsyncsrv"]
+#  raid:
+#    scrub_commands: []       # e.g. ["btrfs scrub status /share/CACHEDEV1_DATA"]
+#  http:
+#    - name: "media_server"
+#      url: "http://localhost:8080/api/status"
+#      headers: {{ Authorization: "Bearer secret" }}
+#      json_pointer: "/busy"
+#      equals: true
+#      interval: "1m"
+#  commands:
+#    - name: "custom_check"
+#      command: "/share/CACHEDEV1_DATA/.scripts/is-busy.sh"
+#      timeout: "10s"
+#  jellyfin:
+#    url: "http://localhost:8096"
+#    api_key: "<api key>"
+#  plex:
+#    url: "http://localhost:32400"
+#    api_key: "<X-Plex-Token>"
+#  qbittorrent:
+#    url: "http://localhost:8080"
+#    username: "admin"        # omit if localhost authentication is bypassed
+#    password: "<password>"
+#    min_rate_kib_per_sec: 50
+#  transmission:
+#    url: "http://localhost:9091/transmission/rpc"
+#    min_rate_kib_per_sec: 50
+#  containers:
+#    socket: "/var/run/docker.sock"
+#    labels: ["nas-boot.inhibit=true"]
+#    busy_containers: []      # e.g. ["handbrake"]
+"#,
+        default_config.bind_addresses[0],
+        format_duration(default_config.shutdown_delay),
+        default_config.keepalive_file,
+        default_config.backup_process_pattern,
+        format_duration(default_config.heartbeat_timeout),
+        format_duration(default_config.clock_skew_warn),
+        format_duration(default_config.check_interval),
+        format_duration(default_config.boot_grace_period),
+        format_duration(default_config.min_uptime),
+        default_config.max_power_cycles_per_day,
+        default_config.stats_file,
+        default_config.power_watts,
+        default_config.energy_tariff_per_kwh,
+        default_config
+            .shutdown_commands
+            .iter()
+            .map(|command| format!("  - \"{command}\""))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        format_duration(default_config.shutdown_verify_timeout),
+        default_config.helper_socket,
+        default_config.http.max_body_bytes,
+        default_config.http.rate_limit_per_minute,
+        format_duration(default_config.http.request_timeout),
+        format_duration(default_config.http.idle_timeout),
+        default_config.http.max_clients,
+        default_config.http.max_connections_per_peer,
+        TlsConfig::default().certificate,
+        TlsConfig::default().private_key,
+        default_config.discovery.enabled,
+        default_config.discovery.port,
+        default_config.server_id_file,
+        default_config.mdns.enabled
+    );
+
+    fs::write(&config_path, yaml_content)
+        .with_context(|| format!("Failed to write config to {}", config_path.display()))?;
+
+    println!(
+        "Generated default configuration at: {}",
+        config_path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,190 @@
+//! UDP responder that lets clients find the server and learn the MAC and IP
+//! addresses to wake and reach it at
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use nas_boot_protocol::{
+    DiscoveryProbe, DiscoveryResponse, NetworkInterface, DISCOVERY_SERVICE, PROTOCOL_VERSION,
+};
+use nix::ifaddrs::getifaddrs;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use crate::guard::{self, Cidr, Guard};
+use crate::AppState;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Probes are a few dozen bytes; anything larger isn't one
+const MAX_PROBE_BYTES: usize = 512;
+
+/// Read the server ID from `path`, generating and saving a new one the first time
+pub fn load_server_id(path: &str) -> Result<String> {
+    if let Ok(id) = fs::read_to_string(path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return Ok(id.to_string());
+        }
+    }
+
+    let mut bytes = [0; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow::anyhow!("Failed to generate a server ID"))?;
+    let id: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    fs::write(path, format!("{id}\n"))
+        .with_context(|| format!("Failed to write server ID to {path}"))?;
+    info!("Generated server ID {id}");
+    Ok(id)
+}
+
+/// Network interfaces with a hardware address and IP addresses, other than loopback
+pub fn network_interfaces() -> Vec<NetworkInterface> {
+    let entries = match fs::read_dir(SYS_CLASS_NET) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to list network interfaces in {SYS_CLASS_NET}: {e}");
+            return Vec::new();
+        }
+    };
+
+    let mut interfaces: Vec<NetworkInterface> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let mac = fs::read_to_string(Path::new(SYS_CLASS_NET).join(&name).join("address"))
+                .ok()?
+                .trim()
+                .to_uppercase();
+            // Loopback, tunnels and the like have no usable hardware address
+            if mac.len() != 17 || mac == "00:00:00:00:00:00" {
+                return None;
+            }
+            Some(NetworkInterface {
+                name,
+                mac,
+                addresses: Vec::new(),
+            })
+        })
+        .collect();
+
+    match getifaddrs() {
+        Ok(addresses) => {
+            for address in addresses {
+                let Some(interface) = interfaces
+                    .iter_mut()
+                    .find(|interface| interface.name == address.interface_name)
+                else {
+                    continue;
+                };
+                let ip = address.address.as_ref().and_then(|address| {
+                    address
+                        .as_sockaddr_in()
+                        .map(|v4| IpAddr::V4(v4.ip()))
+                        .or_else(|| address.as_sockaddr_in6().map(|v6| IpAddr::V6(v6.ip())))
+                });
+                if let Some(ip) = ip {
+                    interface.addresses.push(ip);
+                }
+            }
+        }
+        Err(e) => warn!("Failed to list network addresses: {e}"),
+    }
+
+    // Clients can only use interfaces they can reach
+    interfaces.retain(|interface| !interface.addresses.is_empty());
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    interfaces
+}
+
+/// Bind the discovery port on all IPv4 addresses, where broadcasts arrive
+pub async fn bind(port: u16) -> Result<UdpSocket> {
+    UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+        .await
+        .with_context(|| format!("Failed to bind discovery port {port}"))
+}
+
+/// What every response says about this server, apart from its interfaces
+pub struct Announcement {
+    pub server_id: String,
+    pub api_port: u16,
+    pub cert_fingerprint: Option<String>,
+}
+
+/// Answer probes until the socket fails
+pub async fn serve(socket: UdpSocket, announcement: Announcement, state: AppState) -> Result<()> {
+    let mut buffer = [0; MAX_PROBE_BYTES];
+
+    loop {
+        let (length, peer) = socket
+            .recv_from(&mut buffer)
+            .await
+            .context("Failed to receive discovery probe")?;
+
+        if !accept_probe(
+            &buffer[..length],
+            peer,
+            &state.config.http.allowed_clients,
+            &state.guard,
+        ) {
+            continue;
+        }
+        debug!("Discovery probe from {peer}");
+
+        let response = DiscoveryResponse {
+            service: DISCOVERY_SERVICE.to_string(),
+            server_id: announcement.server_id.clone(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            api_port: announcement.api_port,
+            tls: announcement.cert_fingerprint.is_some(),
+            cert_fingerprint: announcement.cert_fingerprint.clone(),
+            // Read each time, addresses change with DHCP
+            interfaces: network_interfaces(),
+        };
+        let response = serde_json::to_vec(&response)?;
+        if let Err(e) = socket.send_to(&response, peer).await {
+            warn!("Failed to answer discovery probe from {peer}: {e}");
+        }
+    }
+}
+
+/// Whether to answer `datagram` from `peer`. Responses are larger than
+/// probes, so they are rate limited like requests to keep the server from
+/// being used to flood a spoofed source.
+fn accept_probe(
+    datagram: &[u8],
+    peer: SocketAddr,
+    allowed_clients: &[Cidr],
+    guard: &Guard,
+) -> bool {
+    if !guard::allows(allowed_clients, peer.ip()) {
+        guard
+            .rejections
+            .rejected("discovery probe not in allowed_clients", peer.ip());
+        return false;
+    }
+    if !serde_json::from_slice::<DiscoveryProbe>(datagram)
+        .is_ok_and(|probe| probe.service == DISCOVERY_SERVICE)
+    {
+        guard
+            .rejections
+            .rejected("invalid discovery probe", peer.ip());
+        return false;
+    }
+    if !guard.rate_limiter.allow(peer.ip(), Instant::now()) {
+        guard
+            .rejections
+            .rejected("discovery probes rate limited", peer.ip());
+        return false;
+    }
+    true
+}
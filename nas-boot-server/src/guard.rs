@@ -0,0 +1,548 @@
+//! Protection of the HTTP listener against misbehaving or hostile peers
+
+use anyhow::{Context, Result};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::serve::Listener;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::time::{Instant, Sleep};
+use tower::ServiceExt;
+
+use crate::config::HttpConfig;
+
+/// An IPv4 or IPv6 network such as `192.168.42.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // Dual-stack sockets report IPv4 peers as IPv4-mapped IPv6 addresses
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    /// Parse `address/prefix`, or a single address
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid address in '{s}'"))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .with_context(|| format!("Invalid prefix length in '{s}'"))?,
+            None => max_prefix,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Shared state of the protections
+pub struct Guard {
+    pub rate_limiter: RateLimiter,
+    pub rejections: RejectionLog,
+}
+
+impl Guard {
+    pub fn new(config: &HttpConfig) -> Self {
+        Self {
+            rate_limiter: RateLimiter::new(config.rate_limit_per_minute),
+            rejections: RejectionLog::new(REJECTION_LOG_INTERVAL),
+        }
+    }
+}
+
+/// Beyond this many peers, those that haven't used up any of their budget are forgotten
+const MAX_RATE_LIMITED_PEERS: usize = 1024;
+
+/// Token bucket per peer address, refilled at `per_minute` requests per
+/// minute and holding up to a minute's worth
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// A limit of 0 lets every request through
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `peer` may make another request at `now`
+    pub fn allow(&self, peer: IpAddr, now: Instant) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+
+        let peer = peer.to_canonical();
+        let capacity = f64::from(self.per_minute);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * capacity / 60.0).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_RATE_LIMITED_PEERS && !buckets.contains_key(&peer) {
+            buckets.retain(|_, bucket| refill(bucket) < capacity);
+        }
+
+        let bucket = buckets.entry(peer).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Shortest time between two log messages about the same kind of rejection
+const REJECTION_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Logs rejected requests, at most once per interval and kind of rejection,
+/// so that a flood of them doesn't flood the log as well
+pub struct RejectionLog {
+    interval: Duration,
+    reasons: Mutex<HashMap<&'static str, Throttle>>,
+}
+
+struct Throttle {
+    logged: Instant,
+    suppressed: u64,
+}
+
+impl RejectionLog {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            reasons: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn rejected(&self, reason: &'static str, source: impl fmt::Display) {
+        let now = Instant::now();
+        let mut reasons = self.reasons.lock().unwrap_or_else(|e| e.into_inner());
+
+        let suppressed = match reasons.get_mut(reason) {
+            Some(throttle) if now.duration_since(throttle.logged) < self.interval => {
+                throttle.suppressed += 1;
+                return;
+            }
+            Some(throttle) => throttle.suppressed,
+            None => 0,
+        };
+        reasons.insert(
+            reason,
+            Throttle {
+                logged: now,
+                suppressed: 0,
+            },
+        );
+
+        if suppressed > 0 {
+            warn!("Rejected request from {source}: {reason} ({suppressed} more not logged)");
+        } else {
+            warn!("Rejected request from {source}: {reason}");
+        }
+    }
+}
+
+/// TCP listener that turns away peers outside the allowed networks or with
+/// too many connections open, and closes connections that stay idle for too long
+pub struct GuardedListener {
+    listener: TcpListener,
+    allowed_clients: Vec<Cidr>,
+    idle_timeout: Duration,
+    connections: Connections,
+    guard: Arc<Guard>,
+}
+
+impl GuardedListener {
+    pub fn new(listener: TcpListener, config: &HttpConfig, guard: Arc<Guard>) -> Self {
+        Self {
+            listener,
+            allowed_clients: config.allowed_clients.clone(),
+            idle_timeout: config.idle_timeout,
+            connections: Connections::new(config.max_connections_per_peer),
+            guard,
+        }
+    }
+}
+
+/// Whether `peer` is in one of the `allowed_clients` networks; an empty list allows everyone
+pub fn allows(allowed_clients: &[Cidr], peer: IpAddr) -> bool {
+    allowed_clients.is_empty() || allowed_clients.iter().any(|c| c.contains(peer))
+}
+
+impl Listener for GuardedListener {
+    type Io = GuardedStream<tokio::net::TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, peer) = Listener::accept(&mut self.listener).await;
+            if !allows(&self.allowed_clients, peer.ip()) {
+                self.guard
+                    .rejections
+                    .rejected("not in allowed_clients", peer.ip());
+                continue;
+            }
+            let Some(slot) = self.connections.open(peer.ip()) else {
+                self.guard
+                    .rejections
+                    .rejected("too many connections", peer.ip());
+                continue;
+            };
+            return (GuardedStream::new(stream, self.idle_timeout, slot), peer);
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Connections open per peer address
+struct Connections {
+    max_per_peer: usize,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Connections {
+    /// A limit of 0 lets every connection through
+    fn new(max_per_peer: usize) -> Self {
+        Self {
+            max_per_peer,
+            open: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Count another connection from `peer`, unless it has too many open already
+    fn open(&self, peer: IpAddr) -> Option<ConnectionSlot> {
+        let peer = peer.to_canonical();
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(peer).or_insert(0);
+        if self.max_per_peer > 0 && *count >= self.max_per_peer {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            peer,
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// Counts as an open connection of its peer until dropped
+pub struct ConnectionSlot {
+    peer: IpAddr,
+    open: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Address of the peer, for handlers served by [`serve`]
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Serve `app` on `listener` like `axum::serve`, but close connections that
+/// don't deliver the headers of a request within `header_timeout` of
+/// waiting for them, so that a peer can't trickle in a request
+pub async fn serve<L>(mut listener: L, app: Router, header_timeout: Duration) -> io::Result<()>
+where
+    L: Listener<Addr = SocketAddr>,
+{
+    loop {
+        let (stream, peer) = listener.accept().await;
+        let app = app.clone();
+        let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(PeerAddr(peer)));
+            app.clone().oneshot(request.map(Body::new))
+        });
+
+        tokio::spawn(async move {
+            let mut builder = http1::Builder::new();
+            builder
+                .timer(TokioTimer::new())
+                .header_read_timeout(header_timeout);
+            if let Err(e) = builder
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Connection with {peer} ended: {e}");
+            }
+        });
+    }
+}
+
+/// A connection accepted by a [`GuardedListener`]. It counts against the
+/// connections of its peer until dropped, and fails once nothing was read or
+/// written for `timeout`, so that an idle peer can't hold it open.
+pub struct GuardedStream<S> {
+    inner: S,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+    _slot: ConnectionSlot,
+}
+
+impl<S> GuardedStream<S> {
+    fn new(inner: S, timeout: Duration, slot: ConnectionSlot) -> Self {
+        Self {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep(timeout)),
+            _slot: slot,
+        }
+    }
+
+    /// Restart the timeout after progress, or fail if it expired while waiting
+    fn check<T>(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                let deadline = Instant::now() + self.timeout;
+                self.deadline.as_mut().reset(deadline);
+                Poll::Ready(result)
+            }
+            Poll::Pending => match self.deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for GuardedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.check(cx, poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for GuardedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_and_matches_networks() {
+        let lan: Cidr = "192.168.42.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.42.7")));
+        assert!(!lan.contains(ip("192.168.43.7")));
+        // As reported by dual-stack sockets
+        assert!(lan.contains(ip("::ffff:192.168.42.7")));
+        assert!(!lan.contains(ip("fd00::7")));
+        assert_eq!(lan.to_string(), "192.168.42.0/24");
+
+        let ula: Cidr = "fd00::/64".parse().unwrap();
+        assert!(ula.contains(ip("fd00::7")));
+        assert!(!ula.contains(ip("fd00:0:0:1::7")));
+
+        let host: Cidr = " 10.0.0.1 ".parse().unwrap();
+        assert_eq!(host.to_string(), "10.0.0.1/32");
+        assert!(!host.contains(ip("10.0.0.2")));
+
+        let everyone: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everyone.contains(ip("203.0.113.9")));
+
+        for invalid in ["192.168.42.0/33", "fd00::/129", "192.168.42.0/x", "nas", ""] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn refills_a_peer_budget_over_time() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(2);
+        let peer = ip("192.168.42.7");
+
+        assert!(limiter.allow(peer, start));
+        assert!(limiter.allow(peer, start));
+        assert!(!limiter.allow(peer, start));
+        // Other peers have their own budget
+        assert!(limiter.allow(ip("192.168.42.8"), start));
+
+        // Half a minute refills one of two requests per minute
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.allow(peer, later));
+        assert!(!limiter.allow(peer, later));
+
+        // The budget holds at most a minute's worth
+        let much_later = later + Duration::from_secs(600);
+        assert!(limiter.allow(peer, much_later));
+        assert!(limiter.allow(peer, much_later));
+        assert!(!limiter.allow(peer, much_later));
+    }
+
+    #[test]
+    fn unlimited_rate_allows_everything() {
+        let limiter = RateLimiter::new(0);
+        let now = Instant::now();
+        assert!((0..1000).all(|_| limiter.allow(ip("192.168.42.7"), now)));
+    }
+
+    #[test]
+    fn throttles_logging_of_each_kind_of_rejection() {
+        let log = RejectionLog::new(Duration::from_secs(60));
+        for _ in 0..3 {
+            log.rejected("rate limited", "192.168.42.7");
+        }
+        log.rejected("not in allowed_clients", "10.0.0.1");
+
+        let reasons = log.reasons.lock().unwrap();
+        assert_eq!(reasons["rate limited"].suppressed, 2);
+        assert_eq!(reasons["not in allowed_clients"].suppressed, 0);
+    }
+
+    #[test]
+    fn logs_every_rejection_without_an_interval() {
+        let log = RejectionLog::new(Duration::ZERO);
+        for _ in 0..3 {
+            log.rejected("rate limited", "192.168.42.7");
+        }
+        assert_eq!(log.reasons.lock().unwrap()["rate limited"].suppressed, 0);
+    }
+
+    #[test]
+    fn limits_connections_per_peer() {
+        let connections = Connections::new(2);
+        let peer = ip("192.168.42.7");
+
+        let first = connections.open(peer).unwrap();
+        let _second = connections.open(ip("::ffff:192.168.42.7")).unwrap();
+        assert!(connections.open(peer).is_none());
+        assert!(connections.open(ip("192.168.42.8")).is_some());
+
+        drop(first);
+        assert!(connections.open(peer).is_some());
+    }
+
+    #[test]
+    fn forgets_peers_without_connections() {
+        let connections = Connections::new(1);
+        drop(connections.open(ip("192.168.42.7")));
+        assert!(connections.open.lock().unwrap().is_empty());
+    }
+}
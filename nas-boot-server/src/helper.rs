@@ -0,0 +1,276 @@
+//! Privilege separation: a helper process that stays root and does the few
+//! things that need it on behalf of the network-facing server
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use nix::unistd::{self, User};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::process::{Child, Command};
+
+use crate::config::{load_config, Config};
+use crate::inhibitors::{self, OpenFiles};
+use crate::monitor::TierChange;
+use crate::power;
+use crate::stats::Stats;
+
+/// Something the server asks the helper to do
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// Run the shutdown command at this position of `shutdown_commands`
+    Shutdown { index: usize },
+    /// Enter the configured power tier with this name, or leave it again
+    PowerTier { name: String, enter: bool },
+    /// Check for files open on the shares
+    OpenFiles,
+    /// Run the configured `scrub_commands` of the RAID inhibitor
+    ScrubStatus,
+    /// Ask the container API, whose socket only root may use
+    Containers,
+    /// Save the statistics to `stats_file`, whose directory only root may write to
+    SaveStats { stats: Stats },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Done,
+    Failed(String),
+    /// Why an inhibitor checked by the helper keeps the NAS on, if it does
+    Verdict(Option<String>),
+}
+
+/// Longest the server waits for an answer, e.g. while disks spin down
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The running helper, as seen from the server
+pub struct Helper {
+    socket: PathBuf,
+    /// Closing the helper's stdin on exit tells it to stop as well
+    _child: Child,
+}
+
+impl Helper {
+    /// Start the helper while the server still runs as root, and wait until
+    /// it is listening
+    pub async fn spawn(config: &Config) -> Result<Self> {
+        let executable = std::env::current_exe().context("Failed to locate the executable")?;
+        let mut child = Command::new(executable)
+            .arg("privileged-helper")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to start the privileged helper")?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .context("Privileged helper has no stdout")?;
+        let mut line = String::new();
+        tokio::io::BufReader::new(stdout)
+            .read_line(&mut line)
+            .await
+            .context("Failed to wait for the privileged helper")?;
+        if line.trim() != "ready" {
+            return Err(anyhow::anyhow!("Privileged helper failed to start"));
+        }
+
+        Ok(Self {
+            socket: PathBuf::from(&config.helper_socket),
+            _child: child,
+        })
+    }
+
+    /// Send `request` and wait for the response, blocking the calling thread
+    pub fn request(&self, request: &Request) -> Result<Response> {
+        let mut stream = UnixStream::connect(&self.socket).with_context(|| {
+            format!(
+                "Failed to connect to the privileged helper at {}",
+                self.socket.display()
+            )
+        })?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        match serde_json::from_str(&line).context("Invalid response from the privileged helper")? {
+            Response::Failed(message) => Err(anyhow::anyhow!(message)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// Switch the whole process to `user` for good
+pub fn drop_privileges(user: &str) -> Result<()> {
+    let user = lookup_user(user)?;
+
+    let name = CString::new(user.name.as_str())?;
+    unistd::initgroups(&name, user.gid).context("Failed to set supplementary groups")?;
+    unistd::setgid(user.gid).context("Failed to set group ID")?;
+    unistd::setuid(user.uid).context("Failed to set user ID")?;
+
+    info!("Dropped privileges to user {}", user.name);
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<User> {
+    User::from_name(name)
+        .with_context(|| format!("Failed to look up user '{name}'"))?
+        .ok_or_else(|| anyhow::anyhow!("Unknown user '{name}'"))
+}
+
+struct HelperState {
+    config: Config,
+    open_files: Option<Arc<OpenFiles>>,
+}
+
+/// Serve requests from the server until it exits
+pub async fn run_helper() -> Result<()> {
+    let config = load_config()?;
+    let user = lookup_user(
+        config
+            .user
+            .as_deref()
+            .context("No user configured to drop privileges to")?,
+    )?;
+
+    let socket = PathBuf::from(&config.helper_socket);
+    // Left behind if a previous helper was killed
+    let _ = fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("Failed to bind to {}", socket.display()))?;
+
+    // Nobody but the server may talk to the helper
+    std::os::unix::fs::chown(&socket, Some(user.uid.as_raw()), Some(user.gid.as_raw()))
+        .with_context(|| format!("Failed to hand {} to {}", socket.display(), user.name))?;
+    fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+
+    let state = Arc::new(HelperState {
+        open_files: config
+            .inhibitors
+            .open_files
+            .as_ref()
+            .map(|open_files| Arc::new(OpenFiles::new(open_files))),
+        config,
+    });
+
+    // The server closes our stdin when it exits, however it exits
+    let mut server_exited = tokio::spawn(async {
+        let _ = tokio::io::copy(&mut tokio::io::stdin(), &mut tokio::io::sink()).await;
+    });
+
+    println!("ready");
+    info!("Privileged helper listening on {}", socket.display());
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve(stream, state.clone()));
+                }
+                Err(e) => warn!("Privileged helper failed to accept a connection: {e}"),
+            },
+            _ = &mut server_exited => break,
+        }
+    }
+
+    let _ = fs::remove_file(&socket);
+    Ok(())
+}
+
+async fn serve(stream: tokio::net::UnixStream, state: Arc<HelperState>) {
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = String::new();
+    if let Err(e) = tokio::io::BufReader::new(reader).read_line(&mut line).await {
+        warn!("Privileged helper failed to read a request: {e}");
+        return;
+    }
+
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => handle(request, &state).await,
+        Err(e) => Response::Failed(format!("Invalid request: {e}")),
+    };
+
+    let Ok(mut line) = serde_json::to_string(&response) else {
+        return;
+    };
+    line.push('\n');
+    if let Err(e) = writer.write_all(line.as_bytes()).await {
+        warn!("Privileged helper failed to answer: {e}");
+    }
+}
+
+/// Carry out `request`, as far as the configuration allows it
+async fn handle(request: Request, state: &HelperState) -> Response {
+    match request {
+        Request::Shutdown { index } => match state.config.shutdown_commands.get(index) {
+            Some(command) => match power::run_shutdown_command(command).await {
+                Ok(()) => Response::Done,
+                Err(e) => Response::Failed(format!("{e:#}")),
+            },
+            None => Response::Failed(format!("No shutdown command {index} configured")),
+        },
+        Request::PowerTier { name, enter } => {
+            let tiers = state.config.effective_power_tiers();
+            let Some(tier) = tiers.into_iter().find(|tier| tier.name == name) else {
+                return Response::Failed(format!("No power tier '{name}' configured"));
+            };
+            let change = if enter {
+                TierChange::Enter(tier)
+            } else {
+                TierChange::Leave(tier)
+            };
+            match tokio::task::spawn_blocking(move || power::apply(&change)).await {
+                Ok(()) => Response::Done,
+                Err(e) => Response::Failed(e.to_string()),
+            }
+        }
+        Request::OpenFiles => {
+            let Some(open_files) = state.open_files.clone() else {
+                return Response::Verdict(None);
+            };
+            match tokio::task::spawn_blocking(move || open_files.open_files()).await {
+                Ok(reason) => Response::Verdict(reason),
+                Err(e) => Response::Failed(e.to_string()),
+            }
+        }
+        Request::ScrubStatus => {
+            let Some(raid) = state.config.inhibitors.raid.clone() else {
+                return Response::Verdict(None);
+            };
+            match tokio::task::spawn_blocking(move || inhibitors::scrub_status(&raid)).await {
+                Ok(reason) => Response::Verdict(reason),
+                Err(e) => Response::Failed(e.to_string()),
+            }
+        }
+        Request::Containers => match &state.config.inhibitors.containers {
+            Some(containers) => Response::Verdict(inhibitors::container_status(containers).await),
+            None => Response::Verdict(None),
+        },
+        Request::SaveStats { stats } => {
+            let path = PathBuf::from(&state.config.stats_file);
+            match tokio::task::spawn_blocking(move || stats.save(&path)).await {
+                Ok(Ok(())) => Response::Done,
+                Ok(Err(e)) => Response::Failed(format!("{e:#}")),
+                Err(e) => Response::Failed(e.to_string()),
+            }
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use anyhow::{Context, Result};
+use inotify::{Inotify, WatchMask};
+use log::{debug, error, warn};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::helper::{Helper, Request, Response};
+use crate::monitor::{Inhibition, Inhibitors};
+use probes::Cached;
+use services::{Service, ServiceInhibitor};
+
+pub use containers::container_status;
+pub use open_files::OpenFiles;
+pub use raid::scrub_status;
+
+mod containers;
+mod open_files;
+mod probes;
+mod raid;
+mod services;
+mod sessions;
+mod system_load;
+
+/// A single condition that keeps the NAS on
+trait Inhibitor: Send {
+    /// Name under which the inhibitor is counted in the statistics
+    fn name(&self) -> &str;
+
+    /// Take a measurement; called on every monitor tick
+    fn sample(&mut self, _now: Instant) {}
+
+    /// Whether the monitor has to tick periodically to keep this inhibitor
+    /// up to date, rather than only when something happens
+    fn needs_polling(&self) -> bool {
+        false
+    }
+
+    /// Why the NAS has to stay on, if it does
+    fn check(&mut self, now: Instant) -> Option<String>;
+}
+
+/// Inhibitors backed by the live system, checked in the order they were configured
+pub struct SystemInhibitors {
+    inhibitors: Vec<Box<dyn Inhibitor>>,
+    check_interval: Duration,
+}
+
+impl SystemInhibitors {
+    /// Set up the configured inhibitors; `wake` is notified when one of them
+    /// changes in the background. Checks that need root go through `helper`
+    /// if the server dropped its privileges. The keepalive file is watched
+    /// separately, see [`watch_keepalive_file`].
+    pub fn new(config: Arc<Config>, wake: Arc<Notify>, helper: Option<Arc<Helper>>) -> Self {
+        let mut inhibitors: Vec<Box<dyn Inhibitor>> = vec![
+            Box::new(KeepaliveFile {
+                path: config.keepalive_file.clone(),
+            }),
+            Box::new(BackupProcess {
+                pattern: Arc::new(config.backup_process_pattern.clone()),
+                cache: Cached::new(config.check_interval, wake.clone()),
+            }),
+        ];
+
+        let optional = &config.inhibitors;
+        if let Some(cpu_load) = &optional.cpu_load {
+            inhibitors.push(Box::new(system_load::CpuLoad::new(cpu_load)));
+        }
+        if let Some(disk_io) = &optional.disk_io {
+            inhibitors.push(Box::new(system_load::Throughput::disk_io(disk_io)));
+        }
+        if let Some(network) = &optional.network {
+            inhibitors.push(Box::new(system_load::Throughput::network(network)));
+        }
+        if let Some(login_sessions) = &optional.login_sessions {
+            inhibitors.push(Box::new(sessions::LoginSessions::new(login_sessions)));
+        }
+        if let Some(open_files) = &optional.open_files {
+            inhibitors.push(Box::new(open_files::OpenFilesInhibitor::new(
+                open_files,
+                helper.clone(),
+                config.check_interval,
+                wake.clone(),
+            )));
+        }
+        if let Some(raid) = &optional.raid {
+            inhibitors.push(Box::new(raid::RaidMaintenance::new(
+                raid,
+                helper.clone(),
+                wake.clone(),
+            )));
+        }
+        for http in &optional.http {
+            inhibitors.push(Box::new(probes::HttpProbe::new(http, wake.clone())));
+        }
+        for command in &optional.commands {
+            inhibitors.push(Box::new(probes::CommandProbe::new(command, wake.clone())));
+        }
+
+        let services = [
+            (Service::Plex, &optional.plex),
+            (Service::Jellyfin, &optional.jellyfin),
+            (Service::QBittorrent, &optional.qbittorrent),
+            (Service::Transmission, &optional.transmission),
+        ];
+        for (service, config) in services {
+            if let Some(config) = config {
+                inhibitors.push(Box::new(ServiceInhibitor::new(
+                    service,
+                    config,
+                    wake.clone(),
+                )));
+            }
+        }
+        if let Some(containers) = &optional.containers {
+            inhibitors.push(Box::new(containers::Containers::new(
+                containers, helper, wake,
+            )));
+        }
+
+        Self {
+            inhibitors,
+            check_interval: config.check_interval,
+        }
+    }
+}
+
+impl Inhibitors for SystemInhibitors {
+    fn sample(&mut self, now: Instant) {
+        for inhibitor in &mut self.inhibitors {
+            inhibitor.sample(now);
+        }
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        self.inhibitors
+            .iter()
+            .any(|inhibitor| inhibitor.needs_polling())
+            .then_some(self.check_interval)
+    }
+
+    fn active_inhibitor(&mut self, now: Instant) -> Option<Inhibition> {
+        self.inhibitors.iter_mut().find_map(|inhibitor| {
+            inhibitor.check(now).map(|reason| Inhibition {
+                name: inhibitor.name().to_string(),
+                reason,
+            })
+        })
+    }
+}
+
+struct KeepaliveFile {
+    path: String,
+}
+
+impl Inhibitor for KeepaliveFile {
+    fn name(&self) -> &str {
+        "keepalive_file"
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        Path::new(&self.path)
+            .exists()
+            .then(|| format!("keepalive file {} exists", self.path))
+    }
+}
+
+/// Notify `wake` whenever the keepalive file is created or removed.
+///
+/// Watches the parent directory, since the file itself may not exist yet. The
+/// watch lasts as long as the process, so this is called once.
+pub fn watch_keepalive_file(path: &Path, wake: Arc<Notify>) {
+    let (Some(directory), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return;
+    };
+    let directory = directory.to_path_buf();
+    let file_name = file_name.to_os_string();
+
+    let watch = |directory: &PathBuf| -> Result<Inotify> {
+        let inotify = Inotify::init().context("Failed to initialize inotify")?;
+        inotify
+            .watches()
+            .add(
+                directory,
+                WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM,
+            )
+            .with_context(|| format!("Failed to watch {}", directory.display()))?;
+        Ok(inotify)
+    };
+    let mut inotify = match watch(&directory) {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            // The file is still checked whenever the shutdown timer expires
+            warn!("Not watching the keepalive file: {e:#}");
+            return;
+        }
+    };
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Stopped watching the keepalive file: {e}");
+                    return;
+                }
+            };
+            if events
+                .into_iter()
+                .any(|event| event.name == Some(file_name.as_os_str()))
+            {
+                debug!("Keepalive file changed");
+                wake.notify_one();
+            }
+        }
+    });
+}
+
+/// Looks for the backup process with `ps`, in the background
+struct BackupProcess {
+    pattern: Arc<String>,
+    cache: Cached,
+}
+
+impl Inhibitor for BackupProcess {
+    fn name(&self) -> &str {
+        "backup_process"
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        let pattern = self.pattern.clone();
+        self.cache
+            .refresh_blocking(now, move || match Command::new("ps").arg("aux").output() {
+                Ok(output) => String::from_utf8_lossy(&output.stdout)
+                    .contains(pattern.as_str())
+                    .then(|| "backup process is running".to_string()),
+                Err(e) => {
+                    error!("Failed to execute ps command: {e}");
+                    None
+                }
+            });
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        self.cache.verdict()
+    }
+}
+
+/// Have the privileged helper run a check that needs root, blocking the
+/// calling thread
+fn ask_helper(helper: &Helper, request: &Request) -> Option<String> {
+    match helper.request(request) {
+        Ok(Response::Verdict(reason)) => reason,
+        Ok(response) => {
+            warn!("Unexpected response to {request:?}: {response:?}");
+            None
+        }
+        Err(e) => {
+            warn!("Privileged helper failed to answer {request:?}: {e:#}");
+            None
+        }
+    }
+}
+
+/// Run `command` through the shell, killing it if it takes longer than `timeout`.
+///
+/// Meant for status commands with short output; one that fills the pipe
+/// before exiting runs into the timeout.
+fn run_command(command: &str, timeout: Duration) -> Result<Output> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run '{command}'"))?;
+
+    let started = std::time::Instant::now();
+    while child.try_wait()?.is_none() {
+        if started.elapsed() >= timeout {
+            // The child may have exited in the meantime; either way it is reaped below
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow::anyhow!(
+                "'{command}' timed out after {}",
+                humantime::format_duration(timeout)
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    Ok(child.wait_with_output()?)
+}
@@ -0,0 +1,174 @@
+//! Inhibitor that asks the Docker or Podman API which containers want the NAS
+//! to stay on, either through a label or through their health status
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::Notify;
+use tokio::time::{self, Instant};
+
+use super::probes::Cached;
+use super::{ask_helper, Inhibitor};
+use crate::config::ContainerInhibitorConfig;
+use crate::helper::{Helper, Request};
+
+pub struct Containers {
+    config: Arc<ContainerInhibitorConfig>,
+    /// Asks the API if the server dropped its privileges, as the Docker
+    /// socket usually belongs to root
+    helper: Option<Arc<Helper>>,
+    cache: Cached,
+}
+
+impl Containers {
+    pub fn new(
+        config: &ContainerInhibitorConfig,
+        helper: Option<Arc<Helper>>,
+        wake: Arc<Notify>,
+    ) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            helper,
+            cache: Cached::new(config.interval, wake),
+        }
+    }
+}
+
+impl Inhibitor for Containers {
+    fn name(&self) -> &str {
+        "containers"
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        match &self.helper {
+            Some(helper) => {
+                let helper = helper.clone();
+                self.cache
+                    .refresh_blocking(now, move || ask_helper(&helper, &Request::Containers));
+            }
+            None => self.cache.refresh(now, || {
+                let config = self.config.clone();
+                async move { container_status(&config).await }
+            }),
+        }
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        self.cache.verdict()
+    }
+}
+
+/// Describe the containers that keep the NAS on, if any
+pub async fn container_status(config: &ContainerInhibitorConfig) -> Option<String> {
+    match time::timeout(config.timeout, busy_containers(config)).await {
+        Ok(Ok(reasons)) if reasons.is_empty() => None,
+        Ok(Ok(reasons)) => Some(reasons.join(", ")),
+        Ok(Err(e)) => {
+            warn!("Failed to query containers: {e:#}");
+            None
+        }
+        Err(_) => {
+            warn!("Timed out querying containers");
+            None
+        }
+    }
+}
+
+async fn busy_containers(config: &ContainerInhibitorConfig) -> Result<Vec<String>> {
+    let mut reasons = Vec::new();
+
+    // Docker combines several label filters with AND, so query each label separately
+    for label in &config.labels {
+        let filters = json!({ "label": [label], "status": ["running"] }).to_string();
+        let path = format!("/containers/json?filters={}", percent_encode(&filters));
+        let containers = get(&config.socket, &path).await?;
+
+        let names: Vec<&str> = containers
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|container| container.pointer("/Names/0")?.as_str())
+            .map(|name| name.trim_start_matches('/'))
+            .collect();
+        if !names.is_empty() {
+            reasons.push(format!("container {} labelled {label}", names.join(", ")));
+        }
+    }
+
+    for name in &config.busy_containers {
+        let path = format!("/containers/{}/json", percent_encode(name));
+        let container = match get(&config.socket, &path).await {
+            Ok(container) => container,
+            Err(e) => {
+                warn!("Failed to inspect container {name}: {e:#}");
+                continue;
+            }
+        };
+
+        let running = container
+            .pointer("/State/Running")
+            .and_then(Value::as_bool)
+            .unwrap_or_default();
+        let health = container
+            .pointer("/State/Health/Status")
+            .and_then(Value::as_str);
+        if running && health == Some(config.busy_health_status.as_str()) {
+            reasons.push(format!("container {name} is {}", config.busy_health_status));
+        }
+    }
+
+    Ok(reasons)
+}
+
+/// GET `path` from the API behind `socket` and parse the JSON body.
+///
+/// Speaks HTTP/1.0 so the response is neither chunked nor kept alive.
+async fn get(socket: &Path, path: &str) -> Result<Value> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    stream
+        .write_all(format!("GET {path} HTTP/1.0\r\nHost: localhost\r\n\r\n").as_bytes())
+        .await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("Malformed response"))?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Malformed status line"))?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow::anyhow!(
+            "{path} returned status {status}: {}",
+            body.trim()
+        ));
+    }
+
+    serde_json::from_str(body).context("Invalid JSON response")
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
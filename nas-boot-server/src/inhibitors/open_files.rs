@@ -0,0 +1,162 @@
+//! Inhibitor that keeps the NAS on while local processes, such as a media
+//! server or a sync daemon, have files open on the shares
+
+use log::{debug, warn};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::probes::Cached;
+use super::{ask_helper, Inhibitor};
+use crate::config::OpenFilesInhibitorConfig;
+use crate::helper::{Helper, Request};
+
+pub struct OpenFiles {
+    shares: Vec<PathBuf>,
+    ignore_processes: Vec<String>,
+}
+
+impl OpenFiles {
+    pub fn new(config: &OpenFilesInhibitorConfig) -> Self {
+        // Open files are listed by their resolved path, and QNAP shares such as
+        // /share/Multimedia are symlinks into the volume
+        let shares = config
+            .shares
+            .iter()
+            .map(|share| {
+                fs::canonicalize(share).unwrap_or_else(|e| {
+                    warn!("Failed to resolve share {}: {e}", share.display());
+                    share.clone()
+                })
+            })
+            .collect();
+
+        Self {
+            shares,
+            ignore_processes: config.ignore_processes.clone(),
+        }
+    }
+
+    /// Number of handles the process `pid` holds under the shares
+    fn open_handles(&self, pid: &str) -> usize {
+        let Ok(fds) = fs::read_dir(Path::new("/proc").join(pid).join("fd")) else {
+            // The process exited, or belongs to a user we may not inspect
+            return 0;
+        };
+
+        fds.flatten()
+            .filter_map(|fd| fs::read_link(fd.path()).ok())
+            .filter(|target| self.shares.iter().any(|share| target.starts_with(share)))
+            .count()
+    }
+
+    /// Describe the files open on the shares, if there are any
+    pub fn open_files(&self) -> Option<String> {
+        let processes = match fs::read_dir("/proc") {
+            Ok(processes) => processes,
+            Err(e) => {
+                debug!("Failed to list /proc: {e}");
+                return None;
+            }
+        };
+
+        let own_pid = process::id().to_string();
+        let mut handles = 0;
+        let mut holders = BTreeSet::new();
+
+        for entry in processes.flatten() {
+            let pid = entry.file_name().to_string_lossy().into_owned();
+            if !pid.bytes().all(|b| b.is_ascii_digit()) || pid == own_pid {
+                continue;
+            }
+
+            let name = fs::read_to_string(entry.path().join("comm"))
+                .map(|comm| comm.trim_end().to_string())
+                .unwrap_or_default();
+            if self.ignore_processes.contains(&name) {
+                continue;
+            }
+
+            let count = self.open_handles(&pid);
+            if count > 0 {
+                handles += count;
+                holders.insert(name);
+            }
+        }
+
+        (handles > 0).then(|| {
+            let holders: Vec<String> = holders.into_iter().collect();
+            format!(
+                "{handles} open file{} on the shares held by {}",
+                if handles == 1 { "" } else { "s" },
+                holders.join(", ")
+            )
+        })
+    }
+}
+
+/// Where open files are looked for
+enum Scanner {
+    Local(Arc<OpenFiles>),
+    /// Only root may look at the file descriptors of other users' processes
+    Helper(Arc<Helper>),
+}
+
+/// Open files checked in the background, as walking `/proc` takes a while
+pub struct OpenFilesInhibitor {
+    scanner: Scanner,
+    cache: Cached,
+}
+
+impl OpenFilesInhibitor {
+    /// Look for open files through `helper` if the server dropped its privileges
+    pub fn new(
+        config: &OpenFilesInhibitorConfig,
+        helper: Option<Arc<Helper>>,
+        interval: Duration,
+        wake: Arc<Notify>,
+    ) -> Self {
+        let scanner = match helper {
+            Some(helper) => Scanner::Helper(helper),
+            None => Scanner::Local(Arc::new(OpenFiles::new(config))),
+        };
+        Self {
+            scanner,
+            cache: Cached::new(interval, wake),
+        }
+    }
+}
+
+impl Inhibitor for OpenFilesInhibitor {
+    fn name(&self) -> &str {
+        "open_files"
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        match &self.scanner {
+            Scanner::Local(open_files) => {
+                let open_files = open_files.clone();
+                self.cache
+                    .refresh_blocking(now, move || open_files.open_files());
+            }
+            Scanner::Helper(helper) => {
+                let helper = helper.clone();
+                self.cache
+                    .refresh_blocking(now, move || ask_helper(&helper, &Request::OpenFiles));
+            }
+        }
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        self.cache.verdict()
+    }
+}
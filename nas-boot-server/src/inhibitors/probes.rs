@@ -0,0 +1,337 @@
+//! Inhibitors for services the server doesn't know natively: an HTTP endpoint
+//! or a script. Both run in the background so a slow probe can't stall the
+//! monitor, which only ever sees the last verdict.
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde_json::Value;
+use std::future::Future;
+use std::process::Output;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::{run_command, Inhibitor};
+use crate::config::{CommandInhibitorConfig, HttpInhibitorConfig};
+
+/// Verdict of a background probe that is refreshed at most once per interval
+pub(super) struct Cached {
+    interval: Duration,
+    state: Arc<Mutex<CacheState>>,
+    /// Notified when the verdict changes
+    wake: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    verdict: Option<String>,
+    started: Option<Instant>,
+    running: bool,
+}
+
+impl Cached {
+    pub(super) fn new(interval: Duration, wake: Arc<Notify>) -> Self {
+        Self {
+            interval,
+            state: Arc::default(),
+            wake,
+        }
+    }
+
+    /// Start the probe built by `probe` unless one is still running or the
+    /// last one started less than an interval ago
+    pub(super) fn refresh<F, P>(&self, now: Instant, probe: P)
+    where
+        P: FnOnce() -> F,
+        F: Future<Output = Option<String>> + Send + 'static,
+    {
+        let mut state = lock(&self.state);
+        let fresh = state
+            .started
+            .is_some_and(|started| now.duration_since(started) < self.interval);
+        if state.running || fresh {
+            return;
+        }
+        state.running = true;
+        state.started = Some(now);
+
+        let shared = self.state.clone();
+        let wake = self.wake.clone();
+        let probe = probe();
+        tokio::spawn(async move {
+            let verdict = probe.await;
+            let mut state = lock(&shared);
+            if state.verdict != verdict {
+                wake.notify_one();
+            }
+            state.verdict = verdict;
+            state.running = false;
+        });
+    }
+
+    /// Like `refresh`, for a probe that blocks, such as a command or a walk
+    /// through `/proc`, and therefore runs on the blocking thread pool
+    pub(super) fn refresh_blocking<P>(&self, now: Instant, probe: P)
+    where
+        P: FnOnce() -> Option<String> + Send + 'static,
+    {
+        self.refresh(now, || async move {
+            tokio::task::spawn_blocking(probe)
+                .await
+                .unwrap_or_else(|e| {
+                    warn!("Background check failed: {e}");
+                    None
+                })
+        });
+    }
+
+    pub(super) fn verdict(&self) -> Option<String> {
+        lock(&self.state).verdict.clone()
+    }
+}
+
+fn lock(state: &Mutex<CacheState>) -> MutexGuard<'_, CacheState> {
+    // The state stays consistent even if a holder panicked
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub struct HttpProbe {
+    config: Arc<HttpInhibitorConfig>,
+    client: reqwest::Client,
+    cache: Cached,
+}
+
+impl HttpProbe {
+    pub fn new(config: &HttpInhibitorConfig, wake: Arc<Notify>) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            client: reqwest::Client::new(),
+            cache: Cached::new(config.interval, wake),
+        }
+    }
+}
+
+impl Inhibitor for HttpProbe {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        self.cache.refresh(now, || {
+            let client = self.client.clone();
+            let config = self.config.clone();
+            async move {
+                evaluate_http(&client, &config).await.unwrap_or_else(|e| {
+                    warn!("HTTP inhibitor {} failed: {e:#}", config.name);
+                    None
+                })
+            }
+        });
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        self.cache.verdict()
+    }
+}
+
+async fn evaluate_http(
+    client: &reqwest::Client,
+    config: &HttpInhibitorConfig,
+) -> Result<Option<String>> {
+    let mut request = client.get(&config.url).timeout(config.timeout);
+    for (name, value) in &config.headers {
+        request = request.header(name, value);
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to query {}", config.url))?;
+
+    let status = response.status();
+    match config.status {
+        Some(expected) if status.as_u16() != expected => return Ok(None),
+        None if !status.is_success() => return Ok(None),
+        _ => {}
+    }
+
+    let Some(pointer) = &config.json_pointer else {
+        return Ok(Some(format!(
+            "{} answered with status {}",
+            config.name,
+            status.as_u16()
+        )));
+    };
+
+    let body: Value = response
+        .json()
+        .await
+        .with_context(|| format!("Invalid JSON from {}", config.url))?;
+    let Some(value) = body.pointer(pointer) else {
+        return Ok(None);
+    };
+
+    let matched = match &config.equals {
+        Some(expected) => json_equals(value, expected),
+        None => is_truthy(value),
+    };
+    Ok(matched.then(|| format!("{}: {pointer} is {value}", config.name)))
+}
+
+/// Compare numbers by value, so that `equals: 1` matches `1.0`
+fn json_equals(value: &Value, expected: &Value) -> bool {
+    match (value.as_f64(), expected.as_f64()) {
+        (Some(value), Some(expected)) => value == expected,
+        _ => value == expected,
+    }
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(value) => value.as_f64() != Some(0.0),
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        Value::Object(values) => !values.is_empty(),
+    }
+}
+
+pub struct CommandProbe {
+    config: Arc<CommandInhibitorConfig>,
+    cache: Cached,
+}
+
+impl CommandProbe {
+    pub fn new(config: &CommandInhibitorConfig, wake: Arc<Notify>) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            cache: Cached::new(config.interval, wake),
+        }
+    }
+}
+
+impl Inhibitor for CommandProbe {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        self.cache.refresh(now, || {
+            let config = self.config.clone();
+            async move {
+                let command = config.clone();
+                let output = tokio::task::spawn_blocking(move || {
+                    run_command(&command.command, command.timeout)
+                })
+                .await;
+
+                match output {
+                    Ok(Ok(output)) => command_verdict(&config.name, &output),
+                    Ok(Err(e)) => {
+                        warn!("Command inhibitor {} failed: {e:#}", config.name);
+                        None
+                    }
+                    Err(e) => {
+                        warn!("Command inhibitor {} failed: {e}", config.name);
+                        None
+                    }
+                }
+            }
+        });
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        self.cache.verdict()
+    }
+}
+
+/// A command that succeeds keeps the NAS on, with the first line it printed as the reason
+fn command_verdict(name: &str, output: &Output) -> Option<String> {
+    if !output.status.success() {
+        return None;
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let reason = stdout.lines().next().map(str::trim).unwrap_or_default();
+    Some(if reason.is_empty() {
+        format!("{name} reports activity")
+    } else {
+        reason.to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn output(code: i32, stdout: &str) -> Output {
+        Output {
+            // Wait status, with the exit code in the second byte
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn compares_numbers_by_value() {
+        assert!(json_equals(&json!(1.0), &json!(1)));
+        assert!(json_equals(&json!(2), &json!(2)));
+        assert!(!json_equals(&json!(1.5), &json!(1)));
+        assert!(!json_equals(&json!("1"), &json!(1)));
+        assert!(json_equals(&json!("playing"), &json!("playing")));
+        assert!(json_equals(&json!(true), &json!(true)));
+        assert!(!json_equals(&json!(null), &json!(false)));
+    }
+
+    #[test]
+    fn tells_truthy_values() {
+        let cases = [
+            (json!(null), false),
+            (json!(false), false),
+            (json!(true), true),
+            (json!(0), false),
+            (json!(0.0), false),
+            (json!(3), true),
+            (json!(-1.5), true),
+            (json!(""), false),
+            (json!("idle"), true),
+            (json!([]), false),
+            (json!([0]), true),
+            (json!({}), false),
+            (json!({"sessions": 0}), true),
+        ];
+        for (value, truthy) in cases {
+            assert_eq!(is_truthy(&value), truthy, "{value}");
+        }
+    }
+
+    #[test]
+    fn reports_the_first_line_of_a_successful_command() {
+        assert_eq!(
+            command_verdict("sync", &output(0, "  copying photos  \n42 files left\n")),
+            Some("copying photos".to_string())
+        );
+        assert_eq!(
+            command_verdict("sync", &output(0, "")),
+            Some("sync reports activity".to_string())
+        );
+        assert_eq!(
+            command_verdict("sync", &output(0, "\nsecond line")),
+            Some("sync reports activity".to_string())
+        );
+        assert_eq!(command_verdict("sync", &output(1, "not busy")), None);
+    }
+}
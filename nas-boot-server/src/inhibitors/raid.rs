@@ -0,0 +1,293 @@
+//! Inhibitor that keeps the NAS on during RAID maintenance, which would
+//! otherwise restart from scratch on the next boot
+
+use log::{debug, warn};
+use std::fs;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::probes::Cached;
+use super::{ask_helper, run_command, Inhibitor};
+use crate::config::RaidInhibitorConfig;
+use crate::helper::{Helper, Request};
+
+/// mdraid operations reported in `/proc/mdstat`
+const MD_OPERATIONS: &[&str] = &["resync", "recovery", "reshape", "check", "repair"];
+
+pub struct RaidMaintenance {
+    config: Arc<RaidInhibitorConfig>,
+    /// Runs the scrub commands if the server dropped its privileges, as
+    /// e.g. `btrfs scrub status` needs root
+    helper: Option<Arc<Helper>>,
+    /// The scrub commands may take a while, so they run in the background
+    scrubs: Cached,
+}
+
+impl RaidMaintenance {
+    pub fn new(
+        config: &RaidInhibitorConfig,
+        helper: Option<Arc<Helper>>,
+        wake: Arc<Notify>,
+    ) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            helper,
+            scrubs: Cached::new(config.interval, wake),
+        }
+    }
+}
+
+impl Inhibitor for RaidMaintenance {
+    fn name(&self) -> &str {
+        "raid"
+    }
+
+    fn needs_polling(&self) -> bool {
+        !self.config.scrub_commands.is_empty()
+    }
+
+    fn sample(&mut self, now: Instant) {
+        if self.config.scrub_commands.is_empty() {
+            return;
+        }
+        match &self.helper {
+            Some(helper) => {
+                let helper = helper.clone();
+                self.scrubs
+                    .refresh_blocking(now, move || ask_helper(&helper, &Request::ScrubStatus));
+            }
+            None => {
+                let config = self.config.clone();
+                self.scrubs
+                    .refresh_blocking(now, move || scrub_status(&config));
+            }
+        }
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        let mut operations = match fs::read_to_string(&self.config.mdstat_file) {
+            Ok(content) => parse_mdstat(&content),
+            Err(e) => {
+                // Systems without mdraid have no mdstat
+                debug!("Failed to read {}: {e}", self.config.mdstat_file);
+                Vec::new()
+            }
+        };
+        operations.extend(self.scrubs.verdict());
+
+        (!operations.is_empty()).then(|| operations.join(", "))
+    }
+}
+
+/// Describe the scrubs the configured commands report as running, if any
+pub fn scrub_status(config: &RaidInhibitorConfig) -> Option<String> {
+    let scrubs: Vec<String> = config
+        .scrub_commands
+        .iter()
+        .filter_map(|command| {
+            let output = match run_command(command, config.command_timeout) {
+                Ok(output) => output,
+                Err(e) => {
+                    warn!("Failed to check scrub status: {e:#}");
+                    return None;
+                }
+            };
+
+            match parse_scrub_status(&String::from_utf8_lossy(&output.stdout))? {
+                Some(percent) => Some(format!("scrub {percent:.1}% done ({command})")),
+                None => Some(format!("scrub in progress ({command})")),
+            }
+        })
+        .collect();
+
+    (!scrubs.is_empty()).then(|| scrubs.join(", "))
+}
+
+/// Descriptions of the running mdraid operations, such as "md1 resync 12.6% done"
+fn parse_mdstat(content: &str) -> Vec<String> {
+    let mut operations = Vec::new();
+    let mut array = "";
+
+    for line in content.lines() {
+        if let Some((name, _)) = line.split_once(" : ") {
+            if name.starts_with("md") {
+                array = name.trim();
+            }
+            continue;
+        }
+
+        // e.g. "[==>....]  resync = 12.6% (245107200/1943559616) finish=150.2min"
+        // or "resync=DELAYED" while waiting for another array on the same disks
+        let line = line.trim_start_matches(|c: char| c.is_whitespace() || "[=>.]".contains(c));
+        let Some((operation, rest)) = line.split_once('=') else {
+            continue;
+        };
+        let operation = operation.trim();
+        if !MD_OPERATIONS.contains(&operation) {
+            continue;
+        }
+
+        let rest = rest.trim_start();
+        let description = match rest.split_once('%') {
+            Some((percent, _)) if percent.parse::<f64>().is_ok() => {
+                format!("{array} {operation} {percent}% done")
+            }
+            _ => {
+                let state = rest.split_whitespace().next().unwrap_or_default();
+                format!("{array} {operation} {}", state.to_lowercase())
+            }
+        };
+        operations.push(description);
+    }
+
+    operations
+}
+
+/// `Some` with the percentage done, if known, while a scrub or resilver runs.
+///
+/// Understands `btrfs scrub status` ("Status: running", "Bytes scrubbed: ... (45.67%)")
+/// and `zpool status` ("scrub in progress since ...", "..., 45.6% done, ...").
+fn parse_scrub_status(output: &str) -> Option<Option<f64>> {
+    let lower = output.to_lowercase();
+    let running = lower.contains("in progress")
+        || lower.contains("running for")
+        || lower
+            .lines()
+            .any(|line| line.trim_start().starts_with("status:") && line.contains("running"));
+    if !running {
+        return None;
+    }
+
+    let percent = output.split_whitespace().find_map(|word| {
+        word.trim_matches(|c: char| "(),".contains(c))
+            .strip_suffix('%')?
+            .parse::<f64>()
+            .ok()
+    });
+    Some(percent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MDSTAT: &str = "\
+Personalities : [linear] [raid0] [raid1] [raid10] [raid6] [raid5] [raid4] [multipath]
+md1 : active raid1 sda3[0] sdb3[1]
+      1943559616 blocks super 1.0 [2/2] [UU]
+      [==>..................]  resync = 12.6% (245107200/1943559616) finish=150.2min speed=188416K/sec
+
+md2 : active raid1 sdc3[0] sdd3[1]
+      3897063616 blocks super 1.0 [2/2] [UU]
+        resync=DELAYED
+
+md3 : active raid5 sde3[3] sdf3[1] sdg3[0]
+      7804374912 blocks super 1.0 level 5, 512k chunk, algorithm 2 [3/2] [UU_]
+      [>....................]  recovery =  0.3% (11894272/3902187456) finish=434.2min speed=149312K/sec
+
+md13 : active raid1 sda4[0] sdb4[1]
+      458880 blocks super 1.0 [32/2] [UU______________________________]
+      bitmap: 1/1 pages [4KB], 65536KB chunk
+
+unused devices: <none>
+";
+
+    const IDLE_MDSTAT: &str = "\
+Personalities : [raid1]
+md1 : active raid1 sda3[0] sdb3[1]
+      1943559616 blocks super 1.0 [2/2] [UU]
+
+unused devices: <none>
+";
+
+    const BTRFS_RUNNING: &str = "\
+UUID:             4a6b2b1c-5d3e-4f21-9a8b-7c6d5e4f3a2b
+Scrub started:    Sat Oct 17 02:00:01 2026
+Status:           running
+Duration:         0:41:12
+Time left:        1:02:31
+ETA:              Sat Oct 17 03:43:44 2026
+Total to scrub:   3.52TiB
+Bytes scrubbed:   1.41TiB  (40.06%)
+Rate:             597.38MiB/s
+Error summary:    no errors found
+";
+
+    const BTRFS_FINISHED: &str = "\
+UUID:             4a6b2b1c-5d3e-4f21-9a8b-7c6d5e4f3a2b
+Scrub started:    Sat Oct 17 02:00:01 2026
+Status:           finished
+Duration:         1:43:43
+Total to scrub:   3.52TiB
+Rate:             592.15MiB/s
+Error summary:    no errors found
+";
+
+    /// Output of btrfs-progs before 5.0, without a percentage
+    const BTRFS_LEGACY_RUNNING: &str = "\
+scrub status for 4a6b2b1c-5d3e-4f21-9a8b-7c6d5e4f3a2b
+\tscrub started at Sat Oct 17 02:00:01 2026, running for 00:41:12
+\ttotal bytes scrubbed: 1.41TiB with 0 errors
+";
+
+    const ZPOOL_SCRUBBING: &str = "\
+  pool: tank
+ state: ONLINE
+  scan: scrub in progress since Sun Oct 11 00:24:01 2026
+\t1.02T scanned at 1.37G/s, 512G issued at 687M/s, 2.04T total
+\t0B repaired, 24.51% done, 00:38:49 to go
+config:
+
+\tNAME        STATE     READ WRITE CKSUM
+\ttank        ONLINE       0     0     0
+\t  mirror-0  ONLINE       0     0     0
+\t    sda     ONLINE       0     0     0
+\t    sdb     ONLINE       0     0     0
+
+errors: No known data errors
+";
+
+    const ZPOOL_SCRUBBED: &str = "\
+  pool: tank
+ state: ONLINE
+  scan: scrub repaired 0B in 01:43:12 with 0 errors on Sun Oct 11 02:07:13 2026
+config:
+
+\tNAME        STATE     READ WRITE CKSUM
+\ttank        ONLINE       0     0     0
+
+errors: No known data errors
+";
+
+    #[test]
+    fn describes_running_md_operations() {
+        assert_eq!(
+            parse_mdstat(MDSTAT),
+            vec![
+                "md1 resync 12.6% done",
+                "md2 resync delayed",
+                "md3 recovery 0.3% done",
+            ]
+        );
+    }
+
+    #[test]
+    fn idle_arrays_have_no_operations() {
+        assert!(parse_mdstat(IDLE_MDSTAT).is_empty());
+        assert!(parse_mdstat("").is_empty());
+    }
+
+    #[test]
+    fn parses_btrfs_scrub_status() {
+        assert_eq!(parse_scrub_status(BTRFS_RUNNING), Some(Some(40.06)));
+        assert_eq!(parse_scrub_status(BTRFS_LEGACY_RUNNING), Some(None));
+        assert_eq!(parse_scrub_status(BTRFS_FINISHED), None);
+    }
+
+    #[test]
+    fn parses_zpool_status() {
+        assert_eq!(parse_scrub_status(ZPOOL_SCRUBBING), Some(Some(24.51)));
+        assert_eq!(parse_scrub_status(ZPOOL_SCRUBBED), None);
+    }
+}
@@ -0,0 +1,390 @@
+//! Ready-made inhibitors for media servers and torrent clients, which keep the
+//! NAS on while someone is streaming or a download is running
+
+use anyhow::{Context, Result};
+use log::warn;
+use reqwest::header::{HeaderValue, COOKIE, SET_COOKIE};
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use super::probes::Cached;
+use super::Inhibitor;
+use crate::config::ServiceInhibitorConfig;
+
+/// Header Transmission uses to protect its RPC interface against CSRF
+const TRANSMISSION_SESSION_ID: &str = "X-Transmission-Session-Id";
+
+/// Jellyfin lists sessions seen within this many seconds, the same window as
+/// its own dashboard; only those playing something count
+const JELLYFIN_ACTIVE_WITHIN_SECS: &str = "960";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Service {
+    Plex,
+    Jellyfin,
+    QBittorrent,
+    Transmission,
+}
+
+impl Service {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Plex => "plex",
+            Self::Jellyfin => "jellyfin",
+            Self::QBittorrent => "qbittorrent",
+            Self::Transmission => "transmission",
+        }
+    }
+
+    /// Describe `count` active items, e.g. "2 Jellyfin streams"
+    fn describe(self, count: usize) -> String {
+        let (product, item) = match self {
+            Self::Plex => ("Plex", "stream"),
+            Self::Jellyfin => ("Jellyfin", "stream"),
+            Self::QBittorrent => ("qBittorrent", "torrent"),
+            Self::Transmission => ("Transmission", "torrent"),
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        match self {
+            Self::Plex | Self::Jellyfin => format!("{count} {product} {item}{plural}"),
+            Self::QBittorrent | Self::Transmission => {
+                format!("{count} active {product} {item}{plural}")
+            }
+        }
+    }
+}
+
+pub struct ServiceInhibitor {
+    service: Service,
+    config: Arc<ServiceInhibitorConfig>,
+    client: reqwest::Client,
+    cache: Cached,
+}
+
+impl ServiceInhibitor {
+    pub fn new(service: Service, config: &ServiceInhibitorConfig, wake: Arc<Notify>) -> Self {
+        Self {
+            service,
+            config: Arc::new(config.clone()),
+            client: reqwest::Client::new(),
+            cache: Cached::new(config.interval, wake),
+        }
+    }
+}
+
+impl Inhibitor for ServiceInhibitor {
+    fn name(&self) -> &str {
+        self.service.name()
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        self.cache.refresh(now, || {
+            let service = self.service;
+            let client = self.client.clone();
+            let config = self.config.clone();
+            async move {
+                let count = match service {
+                    Service::Plex => plex_streams(&client, &config).await,
+                    Service::Jellyfin => jellyfin_streams(&client, &config).await,
+                    Service::QBittorrent => qbittorrent_torrents(&client, &config).await,
+                    Service::Transmission => transmission_torrents(&client, &config).await,
+                };
+                match count {
+                    Ok(0) => None,
+                    Ok(count) => Some(service.describe(count)),
+                    Err(e) => {
+                        warn!("Failed to query {}: {e:#}", service.name());
+                        None
+                    }
+                }
+            }
+        });
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        self.cache.verdict()
+    }
+}
+
+fn endpoint(config: &ServiceInhibitorConfig, path: &str) -> String {
+    format!("{}{path}", config.url.trim_end_matches('/'))
+}
+
+async fn fetch_json(request: RequestBuilder) -> Result<Value> {
+    let response = request.send().await?.error_for_status()?;
+    response.json().await.context("Invalid JSON response")
+}
+
+/// Sessions currently playing or paused, as listed by `/status/sessions`
+async fn plex_streams(client: &reqwest::Client, config: &ServiceInhibitorConfig) -> Result<usize> {
+    let mut request = client
+        .get(endpoint(config, "/status/sessions"))
+        .header("Accept", "application/json")
+        .timeout(config.timeout);
+    if let Some(token) = &config.api_key {
+        request = request.header("X-Plex-Token", token);
+    }
+
+    Ok(count_plex_streams(&fetch_json(request).await?))
+}
+
+fn count_plex_streams(body: &Value) -> usize {
+    body.pointer("/MediaContainer/size")
+        .and_then(Value::as_u64)
+        .and_then(|size| usize::try_from(size).ok())
+        .unwrap_or_default()
+}
+
+/// Sessions with something playing
+async fn jellyfin_streams(
+    client: &reqwest::Client,
+    config: &ServiceInhibitorConfig,
+) -> Result<usize> {
+    let mut request = client
+        .get(endpoint(config, "/Sessions"))
+        .query(&[("activeWithinSeconds", JELLYFIN_ACTIVE_WITHIN_SECS)])
+        .timeout(config.timeout);
+    if let Some(api_key) = &config.api_key {
+        request = request.header("X-Emby-Token", api_key);
+    }
+
+    Ok(count_jellyfin_streams(&fetch_json(request).await?))
+}
+
+fn count_jellyfin_streams(body: &Value) -> usize {
+    body.as_array()
+        .map(|sessions| {
+            sessions
+                .iter()
+                .filter(|session| session.get("NowPlayingItem").is_some())
+                .count()
+        })
+        .unwrap_or_default()
+}
+
+/// Torrents transferring faster than the configured minimum rate
+async fn qbittorrent_torrents(
+    client: &reqwest::Client,
+    config: &ServiceInhibitorConfig,
+) -> Result<usize> {
+    let mut request = client
+        .get(endpoint(config, "/api/v2/torrents/info"))
+        .query(&[("filter", "active")])
+        .timeout(config.timeout);
+
+    // Without credentials, rely on qBittorrent bypassing authentication for localhost
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        let login = client
+            .post(endpoint(config, "/api/v2/auth/login"))
+            .form(&[("username", username), ("password", password)])
+            .timeout(config.timeout)
+            .send()
+            .await?
+            .error_for_status()?;
+        let session = login
+            .headers()
+            .get(SET_COOKIE)
+            .and_then(|cookie| cookie.to_str().ok())
+            .and_then(|cookie| cookie.split(';').next())
+            .ok_or_else(|| anyhow::anyhow!("qBittorrent login failed"))?;
+        request = request.header(COOKIE, HeaderValue::from_str(session)?);
+    }
+
+    let body = fetch_json(request).await?;
+    Ok(count_fast_torrents(
+        &body,
+        "dlspeed",
+        "upspeed",
+        config.min_rate_kib_per_sec,
+    ))
+}
+
+/// Torrents transferring faster than the configured minimum rate
+async fn transmission_torrents(
+    client: &reqwest::Client,
+    config: &ServiceInhibitorConfig,
+) -> Result<usize> {
+    let payload = json!({
+        "method": "torrent-get",
+        "arguments": { "fields": ["rateDownload", "rateUpload"] },
+    });
+    let request = || {
+        let mut request = client
+            .post(&config.url)
+            .json(&payload)
+            .timeout(config.timeout);
+        if let Some(username) = &config.username {
+            request = request.basic_auth(username, config.password.as_ref());
+        }
+        request
+    };
+
+    // The first request is answered with 409 and the session ID to use
+    let mut response = request().send().await?;
+    if response.status() == StatusCode::CONFLICT {
+        let session_id = response
+            .headers()
+            .get(TRANSMISSION_SESSION_ID)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Transmission did not provide a session ID"))?;
+        response = request()
+            .header(TRANSMISSION_SESSION_ID, session_id)
+            .send()
+            .await?;
+    }
+
+    let body: Value = response
+        .error_for_status()?
+        .json()
+        .await
+        .context("Invalid JSON response")?;
+    Ok(count_transmission_torrents(
+        &body,
+        config.min_rate_kib_per_sec,
+    ))
+}
+
+fn count_transmission_torrents(body: &Value, min_rate_kib_per_sec: f64) -> usize {
+    body.pointer("/arguments/torrents")
+        .map(|torrents| {
+            count_fast_torrents(torrents, "rateDownload", "rateUpload", min_rate_kib_per_sec)
+        })
+        .unwrap_or_default()
+}
+
+/// Count the torrents in a JSON array whose combined rates in bytes per
+/// second exceed `min_rate_kib_per_sec`
+fn count_fast_torrents(
+    torrents: &Value,
+    download: &str,
+    upload: &str,
+    min_rate_kib_per_sec: f64,
+) -> usize {
+    let min_rate = min_rate_kib_per_sec * 1024.0;
+    torrents
+        .as_array()
+        .map(|torrents| {
+            torrents
+                .iter()
+                .filter(|torrent| {
+                    let rate = |field: &str| torrent[field].as_f64().unwrap_or_default();
+                    rate(download) + rate(upload) > min_rate
+                })
+                .count()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/status/sessions` with one episode playing and one movie paused
+    const PLEX_SESSIONS: &str = r#"{
+  "MediaContainer": {
+    "size": 2,
+    "Metadata": [
+      {
+        "type": "episode",
+        "title": "Pilot",
+        "grandparentTitle": "Some Series",
+        "User": { "title": "alice" },
+        "Player": { "product": "Plex for Android (TV)", "state": "playing" }
+      },
+      {
+        "type": "movie",
+        "title": "Some Movie",
+        "User": { "title": "bob" },
+        "Player": { "product": "Plex Web", "state": "paused" }
+      }
+    ]
+  }
+}"#;
+
+    const PLEX_IDLE: &str = r#"{ "MediaContainer": { "size": 0 } }"#;
+
+    /// `/Sessions` with a client playing a movie and an idle browser
+    const JELLYFIN_SESSIONS: &str = r#"[
+  {
+    "Id": "f0c4b1a2",
+    "UserName": "alice",
+    "Client": "Jellyfin Android TV",
+    "DeviceName": "Living Room",
+    "NowPlayingItem": { "Name": "Some Movie", "Type": "Movie" },
+    "PlayState": { "IsPaused": false, "PositionTicks": 123450000 }
+  },
+  {
+    "Id": "9e8d7c6b",
+    "UserName": "bob",
+    "Client": "Jellyfin Web",
+    "DeviceName": "Firefox",
+    "PlayState": { "IsPaused": false }
+  }
+]"#;
+
+    /// `/api/v2/torrents/info?filter=active`; rates in bytes per second
+    const QBITTORRENT_TORRENTS: &str = r#"[
+  { "name": "debian-12.iso", "state": "downloading", "dlspeed": 2097152, "upspeed": 10240 },
+  { "name": "archlinux.iso", "state": "uploading", "dlspeed": 0, "upspeed": 51200 },
+  { "name": "fedora.iso", "state": "stalledDL", "dlspeed": 1024, "upspeed": 0 }
+]"#;
+
+    /// `torrent-get` response; rates in bytes per second
+    const TRANSMISSION_TORRENTS: &str = r#"{
+  "arguments": {
+    "torrents": [
+      { "rateDownload": 0, "rateUpload": 0 },
+      { "rateDownload": 1048576, "rateUpload": 0 },
+      { "rateDownload": 30720, "rateUpload": 30720 }
+    ]
+  },
+  "result": "success"
+}"#;
+
+    fn parse(fixture: &str) -> Value {
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    #[test]
+    fn counts_plex_sessions() {
+        assert_eq!(count_plex_streams(&parse(PLEX_SESSIONS)), 2);
+        assert_eq!(count_plex_streams(&parse(PLEX_IDLE)), 0);
+    }
+
+    #[test]
+    fn counts_jellyfin_sessions_playing_something() {
+        assert_eq!(count_jellyfin_streams(&parse(JELLYFIN_SESSIONS)), 1);
+        assert_eq!(count_jellyfin_streams(&parse("[]")), 0);
+    }
+
+    #[test]
+    fn counts_qbittorrent_torrents_above_the_minimum_rate() {
+        let torrents = parse(QBITTORRENT_TORRENTS);
+        let count = |min_rate| count_fast_torrents(&torrents, "dlspeed", "upspeed", min_rate);
+
+        assert_eq!(count(50.0), 1);
+        assert_eq!(count(0.0), 3);
+        // Exactly the minimum isn't faster than it
+        assert_eq!(count(2058.0), 0);
+    }
+
+    #[test]
+    fn counts_transmission_torrents_above_the_minimum_rate() {
+        let body = parse(TRANSMISSION_TORRENTS);
+
+        assert_eq!(count_transmission_torrents(&body, 50.0), 2);
+        assert_eq!(count_transmission_torrents(&body, 60.0), 1);
+        assert_eq!(count_transmission_torrents(&body, 0.0), 2);
+        assert_eq!(
+            count_transmission_torrents(&parse(r#"{ "result": "success" }"#), 0.0),
+            0
+        );
+    }
+}
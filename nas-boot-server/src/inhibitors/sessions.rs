@@ -0,0 +1,190 @@
+//! Inhibitor that keeps the NAS on while someone is logged in interactively,
+//! e.g. over SSH for maintenance
+
+use log::debug;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+use super::Inhibitor;
+use crate::config::SessionInhibitorConfig;
+
+/// Size of `struct utmp` as written by glibc on Linux, including 64-bit targets
+const UTMP_RECORD_SIZE: usize = 384;
+const USER_PROCESS: i16 = 7;
+
+const PID_OFFSET: usize = 4;
+const LINE_OFFSET: usize = 8;
+const LINE_SIZE: usize = 32;
+const USER_OFFSET: usize = 44;
+const USER_SIZE: usize = 32;
+
+/// An interactive login recorded in utmp
+#[derive(Debug, Clone, PartialEq)]
+struct Session {
+    user: String,
+    /// Terminal relative to `/dev`, e.g. `pts/0`
+    line: String,
+    pid: i32,
+}
+
+pub struct LoginSessions {
+    utmp_file: String,
+    ignore_users: Vec<String>,
+    active_within: Option<Duration>,
+}
+
+impl LoginSessions {
+    pub fn new(config: &SessionInhibitorConfig) -> Self {
+        Self {
+            utmp_file: config.utmp_file.clone(),
+            ignore_users: config.ignore_users.clone(),
+            active_within: config.active_within,
+        }
+    }
+
+    fn counts(&self, session: &Session) -> bool {
+        if self.ignore_users.contains(&session.user) {
+            return false;
+        }
+
+        // utmp keeps stale entries when a session dies without logging out
+        if !Path::new("/proc").join(session.pid.to_string()).exists() {
+            return false;
+        }
+
+        match self.active_within {
+            Some(limit) => terminal_idle_time(&session.line).is_some_and(|idle| idle <= limit),
+            None => true,
+        }
+    }
+}
+
+impl Inhibitor for LoginSessions {
+    fn name(&self) -> &str {
+        "login_sessions"
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        let content = match fs::read(&self.utmp_file) {
+            Ok(content) => content,
+            Err(e) => {
+                debug!("Failed to read {}: {e}", self.utmp_file);
+                return None;
+            }
+        };
+
+        let sessions: Vec<String> = parse_utmp(&content)
+            .into_iter()
+            .filter(|session| self.counts(session))
+            .map(|session| format!("{} on {}", session.user, session.line))
+            .collect();
+
+        match sessions.len() {
+            0 => None,
+            1 => Some(format!("login session of {}", sessions[0])),
+            count => Some(format!("{count} login sessions: {}", sessions.join(", "))),
+        }
+    }
+}
+
+fn parse_utmp(content: &[u8]) -> Vec<Session> {
+    let (records, _) = content.as_chunks::<UTMP_RECORD_SIZE>();
+    records
+        .iter()
+        .filter(|record| i16::from_ne_bytes([record[0], record[1]]) == USER_PROCESS)
+        .map(|record| Session {
+            user: c_string(&record[USER_OFFSET..USER_OFFSET + USER_SIZE]),
+            line: c_string(&record[LINE_OFFSET..LINE_OFFSET + LINE_SIZE]),
+            pid: i32::from_ne_bytes(
+                record[PID_OFFSET..PID_OFFSET + 4]
+                    .try_into()
+                    .unwrap_or_default(),
+            ),
+        })
+        .collect()
+}
+
+/// A fixed-size field that is NUL-terminated unless it fills the whole field
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Time since the terminal last saw input, like the IDLE column of `w`
+fn terminal_idle_time(line: &str) -> Option<Duration> {
+    let accessed = fs::metadata(Path::new("/dev").join(line))
+        .and_then(|metadata| metadata.accessed())
+        .ok()?;
+    Some(
+        SystemTime::now()
+            .duration_since(accessed)
+            .unwrap_or_default(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOT_TIME: i16 = 2;
+    const LOGIN_PROCESS: i16 = 6;
+    const DEAD_PROCESS: i16 = 8;
+    const HOST_OFFSET: usize = 76;
+
+    /// A record laid out like glibc's `struct utmp` on x86_64
+    fn record(kind: i16, pid: i32, line: &str, user: &str, host: &str) -> Vec<u8> {
+        let mut record = vec![0; UTMP_RECORD_SIZE];
+        record[..2].copy_from_slice(&kind.to_ne_bytes());
+        record[PID_OFFSET..PID_OFFSET + 4].copy_from_slice(&pid.to_ne_bytes());
+        record[LINE_OFFSET..LINE_OFFSET + line.len()].copy_from_slice(line.as_bytes());
+        record[USER_OFFSET..USER_OFFSET + user.len()].copy_from_slice(user.as_bytes());
+        record[HOST_OFFSET..HOST_OFFSET + host.len()].copy_from_slice(host.as_bytes());
+        record
+    }
+
+    #[test]
+    fn parses_user_processes_only() {
+        let content = [
+            record(BOOT_TIME, 0, "~", "reboot", "5.10.60-qnap"),
+            record(LOGIN_PROCESS, 1021, "tty1", "LOGIN", ""),
+            record(USER_PROCESS, 4242, "pts/0", "admin", "192.168.1.20"),
+            record(DEAD_PROCESS, 3901, "pts/1", "", ""),
+            record(USER_PROCESS, 5117, "pts/2", "backup", "nas2.local"),
+        ]
+        .concat();
+
+        assert_eq!(
+            parse_utmp(&content),
+            vec![
+                Session {
+                    user: "admin".to_string(),
+                    line: "pts/0".to_string(),
+                    pid: 4242,
+                },
+                Session {
+                    user: "backup".to_string(),
+                    line: "pts/2".to_string(),
+                    pid: 5117,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_fields_that_fill_their_whole_size() {
+        let user = "a".repeat(USER_SIZE);
+        let content = record(USER_PROCESS, 1, "pts/0", &user, "");
+
+        assert_eq!(parse_utmp(&content)[0].user, user);
+    }
+
+    #[test]
+    fn ignores_a_truncated_record() {
+        let mut content = record(USER_PROCESS, 4242, "pts/0", "admin", "");
+        content.extend(&record(USER_PROCESS, 4243, "pts/1", "admin", "")[..100]);
+
+        assert_eq!(parse_utmp(&content).len(), 1);
+    }
+}
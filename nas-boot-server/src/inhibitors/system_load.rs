@@ -0,0 +1,398 @@
+//! Inhibitors that keep the NAS on while it is busy by itself, such as during
+//! a transcode or a scrub, judged by `/proc/loadavg`, `/proc/diskstats` and
+//! `/proc/net/dev`
+
+use humantime::format_duration;
+use log::debug;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::Inhibitor;
+use crate::config::{LoadInhibitorConfig, ThroughputInhibitorConfig};
+
+/// `/proc/diskstats` counts 512-byte sectors regardless of the device
+const SECTOR_SIZE: u64 = 512;
+
+const KIB: f64 = 1024.0;
+
+/// A value measured from `since` until `until`
+struct Sample {
+    since: Instant,
+    until: Instant,
+    value: f64,
+}
+
+/// Samples of a measurement over a sliding time window. Ticks come at
+/// irregular times, so samples can be clustered.
+struct Window {
+    length: Duration,
+    samples: VecDeque<Sample>,
+    first_sample: Option<Instant>,
+}
+
+impl Window {
+    fn new(length: Duration) -> Self {
+        Self {
+            length,
+            samples: VecDeque::new(),
+            first_sample: None,
+        }
+    }
+
+    /// Add `value`, measured from `since` until `now`
+    fn push(&mut self, since: Instant, now: Instant, value: f64) {
+        self.first_sample.get_or_insert(since);
+        self.samples.push_back(Sample {
+            since,
+            until: now,
+            value,
+        });
+        while let Some(sample) = self.samples.front() {
+            if now.duration_since(sample.until) <= self.length {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// The samples, once sampling has covered a full window, with the start of the window
+    fn covered(&self) -> Option<(Instant, impl Iterator<Item = &Sample>)> {
+        let latest = self.samples.back()?.until;
+        if latest.duration_since(self.first_sample?) < self.length {
+            return None;
+        }
+        let start = latest.checked_sub(self.length).unwrap_or(latest);
+        Some((start, self.samples.iter()))
+    }
+
+    /// Average over the window, each sample weighted by the part of the window it covers
+    fn mean(&self) -> Option<f64> {
+        let (start, samples) = self.covered()?;
+        let (sum, covered) = samples.fold((0.0, 0.0), |(sum, covered), sample| {
+            let weight = sample
+                .until
+                .saturating_duration_since(sample.since.max(start))
+                .as_secs_f64();
+            (sum + sample.value * weight, covered + weight)
+        });
+        (covered > 0.0).then(|| sum / covered)
+    }
+
+    /// Lowest value over the window
+    fn min(&self) -> Option<f64> {
+        let (_, samples) = self.covered()?;
+        samples.map(|sample| sample.value).reduce(f64::min)
+    }
+}
+
+pub struct CpuLoad {
+    threshold: f64,
+    window: Window,
+}
+
+impl CpuLoad {
+    pub fn new(config: &LoadInhibitorConfig) -> Self {
+        Self {
+            threshold: config.threshold,
+            window: Window::new(config.window),
+        }
+    }
+}
+
+impl Inhibitor for CpuLoad {
+    fn name(&self) -> &str {
+        "cpu_load"
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        match fs::read_to_string("/proc/loadavg")
+            .ok()
+            .and_then(|content| parse_loadavg(&content))
+        {
+            Some(load) => self.window.push(now, now, load),
+            None => debug!("Failed to read /proc/loadavg"),
+        }
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        // The load average is smoothed already; it has to stay above the
+        // threshold, so that a spike doesn't keep the NAS on
+        let load = self.window.min()?;
+        (load > self.threshold).then(|| {
+            format!(
+                "load average {load:.2} or more, above {:.2}, over the last {}",
+                self.threshold,
+                format_duration(self.window.length)
+            )
+        })
+    }
+}
+
+/// Throughput of a monotonically increasing byte counter, such as the bytes
+/// transferred by a set of disks or network interfaces
+pub struct Throughput {
+    name: &'static str,
+    description: &'static str,
+    read_counter: fn(&[String]) -> Option<u64>,
+    devices: Vec<String>,
+    threshold_kib_per_sec: f64,
+    window: Window,
+    last: Option<(Instant, u64)>,
+}
+
+impl Throughput {
+    pub fn disk_io(config: &ThroughputInhibitorConfig) -> Self {
+        Self::new("disk_io", "disk I/O", read_disk_bytes, config)
+    }
+
+    pub fn network(config: &ThroughputInhibitorConfig) -> Self {
+        Self::new("network", "network traffic", read_network_bytes, config)
+    }
+
+    fn new(
+        name: &'static str,
+        description: &'static str,
+        read_counter: fn(&[String]) -> Option<u64>,
+        config: &ThroughputInhibitorConfig,
+    ) -> Self {
+        Self {
+            name,
+            description,
+            read_counter,
+            devices: config.devices.clone(),
+            threshold_kib_per_sec: config.threshold_kib_per_sec,
+            window: Window::new(config.window),
+            last: None,
+        }
+    }
+}
+
+impl Inhibitor for Throughput {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn needs_polling(&self) -> bool {
+        true
+    }
+
+    fn sample(&mut self, now: Instant) {
+        let Some(total) = (self.read_counter)(&self.devices) else {
+            debug!("Failed to read {} counters", self.description);
+            return;
+        };
+
+        if let Some((previous_time, previous_total)) = self.last {
+            let elapsed = now.duration_since(previous_time).as_secs_f64();
+            if elapsed > 0.0 {
+                // Counters restart when a device is re-attached, count that as idle
+                let bytes = total.saturating_sub(previous_total);
+                self.window
+                    .push(previous_time, now, bytes as f64 / KIB / elapsed);
+            }
+        }
+        self.last = Some((now, total));
+    }
+
+    fn check(&mut self, _now: Instant) -> Option<String> {
+        let rate = self.window.mean()?;
+        (rate > self.threshold_kib_per_sec).then(|| {
+            format!(
+                "{} at {rate:.0} KiB/s above {:.0} KiB/s over the last {}",
+                self.description,
+                self.threshold_kib_per_sec,
+                format_duration(self.window.length)
+            )
+        })
+    }
+}
+
+fn parse_loadavg(content: &str) -> Option<f64> {
+    content.split_whitespace().next()?.parse().ok()
+}
+
+fn read_disk_bytes(devices: &[String]) -> Option<u64> {
+    let content = fs::read_to_string("/proc/diskstats").ok()?;
+    Some(parse_diskstats(&content, devices))
+}
+
+/// Bytes read and written by `devices`, or by all physical disks if empty
+fn parse_diskstats(content: &str, devices: &[String]) -> u64 {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let name = *fields.get(2)?;
+            let selected = if devices.is_empty() {
+                is_physical_disk(name)
+            } else {
+                devices.iter().any(|device| device == name)
+            };
+            if !selected {
+                return None;
+            }
+
+            let sectors_read: u64 = fields.get(5)?.parse().ok()?;
+            let sectors_written: u64 = fields.get(9)?.parse().ok()?;
+            Some((sectors_read + sectors_written) * SECTOR_SIZE)
+        })
+        .sum()
+}
+
+/// Whole disks backed by hardware; skips partitions, loop, RAM and device-mapper devices
+fn is_physical_disk(name: &str) -> bool {
+    Path::new("/sys/block").join(name).join("device").exists()
+}
+
+fn read_network_bytes(interfaces: &[String]) -> Option<u64> {
+    let content = fs::read_to_string("/proc/net/dev").ok()?;
+    Some(parse_net_dev(&content, interfaces))
+}
+
+/// Bytes received and sent on `interfaces`, or on all but loopback if empty
+fn parse_net_dev(content: &str, interfaces: &[String]) -> u64 {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (name, counters) = line.split_once(':')?;
+            let name = name.trim();
+            let selected = if interfaces.is_empty() {
+                name != "lo"
+            } else {
+                interfaces.iter().any(|interface| interface == name)
+            };
+            if !selected {
+                return None;
+            }
+
+            let fields: Vec<&str> = counters.split_whitespace().collect();
+            let received: u64 = fields.first()?.parse().ok()?;
+            let sent: u64 = fields.get(8)?.parse().ok()?;
+            Some(received + sent)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISKSTATS: &str = "\
+   8       0 sda 96153 3525 7406246 121506 38372 41853 2523992 120183 0 109810 259743 0 0 0 0 4143 18053
+   8       1 sda1 420 0 27672 187 2 0 2 0 0 213 187 0 0 0 0 0 0
+   8      16 sdb 1024 17 40960 1000 300 12 8000 200 0 900 1200 0 0 0 0 0 0
+ 253       0 dm-0 89521 0 7164530 117460 80249 0 2523984 256908 0 108796 374368 0 0 0 0 0 0
+";
+
+    const NET_DEV: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  482930    5117    0    0    0     0          0         0   482930    5117    0    0    0     0       0          0
+  eth0: 1587402873 1204662    0    4    0     0          0     15332 98735431  483021    0    0    0     0       0          0
+docker0:       0       0    0    0    0     0          0         0     2876      24    0    0    0     0       0          0
+";
+
+    fn devices(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_loadavg() {
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/512 12345\n"), Some(0.52));
+        assert_eq!(parse_loadavg(""), None);
+    }
+
+    #[test]
+    fn sums_sectors_of_selected_disks() {
+        let sda = (7_406_246 + 2_523_992) * SECTOR_SIZE;
+        let sdb = (40_960 + 8_000) * SECTOR_SIZE;
+
+        assert_eq!(parse_diskstats(DISKSTATS, &devices(&["sda"])), sda);
+        assert_eq!(
+            parse_diskstats(DISKSTATS, &devices(&["sda", "sdb"])),
+            sda + sdb
+        );
+        assert_eq!(parse_diskstats(DISKSTATS, &devices(&["sdz"])), 0);
+    }
+
+    #[test]
+    fn sums_bytes_of_all_interfaces_but_loopback() {
+        let eth0 = 1_587_402_873 + 98_735_431;
+        let docker0 = 2_876;
+
+        assert_eq!(parse_net_dev(NET_DEV, &[]), eth0 + docker0);
+        assert_eq!(parse_net_dev(NET_DEV, &devices(&["eth0"])), eth0);
+        assert_eq!(parse_net_dev(NET_DEV, &devices(&["lo"])), 2 * 482_930);
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn averages_once_the_window_is_covered() {
+        let start = Instant::now();
+        let mut window = Window::new(secs(60));
+
+        window.push(start, start + secs(30), 1.0);
+        assert_eq!(window.mean(), None);
+
+        window.push(start + secs(30), start + secs(60), 3.0);
+        assert_eq!(window.mean(), Some(2.0));
+
+        // Half of the first sample has left the window
+        window.push(start + secs(60), start + secs(75), 5.0);
+        assert_eq!(window.mean(), Some((15.0 + 90.0 + 75.0) / 60.0));
+
+        // The first sample has left it entirely
+        window.push(start + secs(75), start + secs(100), 7.0);
+        assert_eq!(
+            window.mean(),
+            Some((20.0 * 3.0 + 15.0 * 5.0 + 25.0 * 7.0) / 60.0)
+        );
+    }
+
+    #[test]
+    fn weights_unevenly_spaced_samples_by_their_time() {
+        let start = Instant::now();
+        let mut window = Window::new(secs(60));
+
+        // A burst measured over two seconds by clustered ticks doesn't
+        // outweigh the idle time around it
+        window.push(start, start + secs(50), 10.0);
+        window.push(start + secs(50), start + secs(51), 1000.0);
+        window.push(start + secs(51), start + secs(52), 1000.0);
+        window.push(start + secs(52), start + secs(60), 10.0);
+        assert_eq!(window.mean(), Some(43.0));
+    }
+
+    #[test]
+    fn load_has_to_stay_above_the_threshold() {
+        let start = Instant::now();
+        let mut load = CpuLoad::new(&LoadInhibitorConfig {
+            threshold: 2.0,
+            window: secs(60),
+        });
+
+        // Clustered samples of a spike don't make up for the quiet minute
+        for offset in [0, 1, 2, 3] {
+            load.window
+                .push(start + secs(offset), start + secs(offset), 8.0);
+        }
+        load.window.push(start + secs(60), start + secs(60), 0.5);
+        assert_eq!(load.check(start + secs(60)), None);
+
+        for offset in [70, 90, 120, 121] {
+            load.window
+                .push(start + secs(offset), start + secs(offset), 3.0);
+        }
+        assert!(load.check(start + secs(121)).is_some());
+    }
+}
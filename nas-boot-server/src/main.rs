@@ -1,26 +1,56 @@
 use anyhow::{Context, Result};
-use axum::{routing::post, Json, Router};
-use chrono::{DateTime, Utc};
+use chrono::Local;
 use clap::{Parser, Subcommand};
-use log::{debug, error, info, Level, Log, Metadata, Record};
+use log::{debug, error, info, warn, Level, Log, Metadata, Record};
 use multi_log::MultiLogger;
-use serde::{Deserialize, Serialize};
+use nas_boot_protocol::Capability;
+use socket2::{Domain, Socket, Type};
 use std::collections::HashMap;
 use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
-use tokio::time;
-use yaml_rust2::YamlLoader;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+
+mod api;
+mod config;
+mod discovery;
+mod guard;
+mod helper;
+mod inhibitors;
+mod mdns;
+mod monitor;
+mod power;
+mod simulate;
+mod stats;
+mod tls;
+mod ups;
+
+use config::{generate_config, load_config, load_config_from, Config, PowerTier};
+use guard::{Guard, GuardedListener};
+use helper::{Helper, Request};
+use inhibitors::SystemInhibitors;
+use monitor::{Event, Inhibitors, Monitor, MonitorStatus, Observation, TierChange};
+use stats::Stats;
+use tls::TlsListener;
+use ups::PowerStatus;
+
+/// The mDNS responder logs every packet it handles below warning level
+const MDNS_LOG_TARGET: &str = "mdns_sd";
 
 // Custom QNAP Logger
 pub struct QnapLogger;
 
 impl Log for QnapLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        !metadata.target().starts_with(MDNS_LOG_TARGET) || metadata.level() <= Level::Warn
     }
 
     fn log(&self, record: &Record) {
@@ -48,11 +78,6 @@ impl Log for QnapLogger {
     }
 }
 
-#[cfg(unix)]
-use std::os::unix::process::ExitStatusExt;
-#[cfg(windows)]
-use std::os::windows::process::ExitStatusExt;
-
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -64,134 +89,73 @@ struct Cli {
 enum Commands {
     /// Generate default configuration file
     GenerateConfig,
+    /// Generate a self-signed TLS certificate and print its fingerprint
+    GenerateCert {
+        /// Hostname or IP address the certificate is valid for; may be repeated
+        #[arg(long = "name", value_name = "NAME")]
+        names: Vec<String>,
+
+        /// Replace an existing certificate and private key
+        #[arg(long)]
+        force: bool,
+    },
     /// Run the server
     Run,
+    /// Show uptime and energy-saving statistics
+    Stats {
+        /// Only include the last N days
+        #[arg(long)]
+        days: Option<usize>,
+
+        /// Export the statistics as CSV to this file
+        #[arg(long, value_name = "FILE")]
+        csv: Option<PathBuf>,
+    },
+    /// Replay a recorded heartbeat trace against the shutdown logic
+    Simulate {
+        /// Trace file (JSON array or CSV with columns timestamp,event,name,active)
+        trace: PathBuf,
+
+        /// Configuration to simulate instead of the installed one
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        /// Override the shutdown delay, e.g. "20m"
+        #[arg(long, value_parser = humantime::parse_duration)]
+        shutdown_delay: Option<Duration>,
+
+        /// Time from power-on until the server accepts heartbeats
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "2m")]
+        boot_duration: Duration,
+    },
+    /// Serve the privileged requests of a server that dropped its privileges
+    #[command(hide = true)]
+    PrivilegedHelper,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Config {
-    bind_address: String,
-    shutdown_delay_mins: i64,
-    keepalive_file: String,
-    backup_process_pattern: String,
-    heartbeat_timeout_mins: i64,
-    check_interval_secs: u64,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            bind_address: "0.0.0.0:8090".to_string(),
-            shutdown_delay_mins: 10,
-            keepalive_file: "/share/Public/keepalive.txt".to_string(),
-            backup_process_pattern:
-                "python /share/CACHEDEV1_DATA/.qpkg/AzureStorage/bin/engine.pyc backup".to_string(),
-            heartbeat_timeout_mins: 2,
-            check_interval_secs: 60,
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Heartbeat {
-    timestamp: String,
-    hostname: String,
+/// What the server knows about a client from its heartbeats
+#[derive(Debug, Clone)]
+struct ClientInfo {
+    /// Server receive time of the last heartbeat
+    last_seen: Instant,
+    /// Client clock minus server clock at the last heartbeat, if the timestamp was valid
+    clock_skew: Option<chrono::Duration>,
+    protocol_version: u32,
+    capabilities: Vec<Capability>,
 }
 
 #[derive(Clone)]
 struct AppState {
-    clients: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    clients: Arc<Mutex<HashMap<String, ClientInfo>>>,
     config: Arc<Config>,
-}
-
-fn get_config_path() -> PathBuf {
-    PathBuf::from("/share/CACHEDEV1_DATA/.config/nas-boot/nas-boot-server-config.yaml")
-}
-
-fn load_config() -> Result<Config> {
-    let config_path = get_config_path();
-
-    if !config_path.exists() {
-        return Err(anyhow::anyhow!(
-            "Configuration file not found at: {}. Run with 'generate-config' to create it.",
-            config_path.display()
-        ));
-    }
-
-    let config_str = fs::read_to_string(&config_path)
-        .with_context(|| format!("Failed to read config from {}", config_path.display()))?;
-
-    let docs = YamlLoader::load_from_str(&config_str).context("Failed to parse YAML")?;
-
-    if docs.is_empty() {
-        return Err(anyhow::anyhow!("Empty configuration file"));
-    }
-
-    let doc = &docs[0];
-
-    let config = Config {
-        bind_address: doc["bind_address"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing bind_address"))?
-            .to_string(),
-        shutdown_delay_mins: doc["shutdown_delay_mins"]
-            .as_i64()
-            .ok_or_else(|| anyhow::anyhow!("Missing shutdown_delay_mins"))?,
-        keepalive_file: doc["keepalive_file"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing keepalive_file"))?
-            .to_string(),
-        backup_process_pattern: doc["backup_process_pattern"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing backup_process_pattern"))?
-            .to_string(),
-        heartbeat_timeout_mins: doc["heartbeat_timeout_mins"]
-            .as_i64()
-            .ok_or_else(|| anyhow::anyhow!("Missing heartbeat_timeout_mins"))?,
-        check_interval_secs: doc["check_interval_secs"]
-            .as_i64()
-            .ok_or_else(|| anyhow::anyhow!("Missing check_interval_secs"))?
-            as u64,
-    };
-
-    Ok(config)
-}
-
-fn generate_config() -> Result<()> {
-    let config_path = get_config_path();
-
-    if let Some(parent) = config_path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
-    }
-
-    let default_config = Config::default();
-
-    // Create YAML manually
-    let yaml_content = format!(
-        r#"bind_address: "{}"
-shutdown_delay_mins: {}
-keepalive_file: "{}"
-backup_process_pattern: "{}"
-heartbeat_timeout_mins: {}
-check_interval_secs: {}
-"#,
-        default_config.bind_address,
-        default_config.shutdown_delay_mins,
-        default_config.keepalive_file,
-        default_config.backup_process_pattern,
-        default_config.heartbeat_timeout_mins,
-        default_config.check_interval_secs
-    );
-
-    fs::write(&config_path, yaml_content)
-        .with_context(|| format!("Failed to write config to {}", config_path.display()))?;
-
-    println!(
-        "Generated default configuration at: {}",
-        config_path.display()
-    );
-    Ok(())
+    stats: Arc<Mutex<Stats>>,
+    status: Arc<Mutex<MonitorStatus>>,
+    power: Arc<Mutex<PowerStatus>>,
+    /// Wakes the shutdown monitor when something relevant changed
+    wake: Arc<Notify>,
+    /// Does the privileged work once the server runs as `config.user`
+    helper: Option<Arc<Helper>>,
+    guard: Arc<Guard>,
 }
 
 #[tokio::main]
@@ -199,6 +163,7 @@ async fn main() -> Result<()> {
     // Create console logger
     let console_logger = env_logger::Builder::from_default_env()
         .filter_level(log::LevelFilter::Debug)
+        .filter_module(MDNS_LOG_TARGET, log::LevelFilter::Warn)
         .build();
 
     // Create QNAP logger
@@ -213,6 +178,15 @@ async fn main() -> Result<()> {
 
     let result = match cli.command {
         Some(Commands::GenerateConfig) => generate_config(),
+        Some(Commands::GenerateCert { names, force }) => generate_cert(names, force),
+        Some(Commands::Stats { days, csv }) => show_stats(days, csv.as_deref()),
+        Some(Commands::Simulate {
+            trace,
+            config,
+            shutdown_delay,
+            boot_duration,
+        }) => run_simulation(&trace, config.as_deref(), shutdown_delay, boot_duration),
+        Some(Commands::PrivilegedHelper) => helper::run_helper().await,
         Some(Commands::Run) | None => run_server().await,
     };
 
@@ -229,132 +203,454 @@ async fn run_server() -> Result<()> {
 
     let config = load_config()?;
 
+    let stats = Stats::load(Path::new(&config.stats_file))?;
+
+    // Read the certificate while the server may still run as root
+    let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
+
+    let helper = match &config.user {
+        Some(_) => Some(Arc::new(Helper::spawn(&config).await?)),
+        None => None,
+    };
+
     let state = AppState {
         clients: Arc::new(Mutex::new(HashMap::new())),
         config: Arc::new(config.clone()),
+        stats: Arc::new(Mutex::new(stats)),
+        status: Arc::new(Mutex::new(MonitorStatus::default())),
+        power: Arc::new(Mutex::new(PowerStatus::default())),
+        wake: Arc::new(Notify::new()),
+        helper,
+        guard: Arc::new(Guard::new(&config.http)),
     };
 
+    if let Some(ups) = config.ups.clone() {
+        tokio::spawn(ups::watch_ups(ups, state.power.clone(), state.wake.clone()));
+    }
+
     // Start shutdown monitor
-    let monitor_state = state.clone();
-    tokio::spawn(async move {
-        shutdown_monitor(monitor_state).await;
-    });
+    tokio::spawn(supervise_monitor(state.clone()));
 
     // Start web server
-    let app = Router::new()
-        .route("/heartbeat", post(handle_heartbeat))
-        .with_state(state);
+    let app = api::router(state.clone());
+
+    // Bind what is available now, while the server may still run as root;
+    // the other addresses are retried in the background
+    let mut listeners = Vec::new();
+    for &address in &config.bind_addresses {
+        // An IPv6 listener would take over the IPv4 port listed next to it
+        let only_v6 = config
+            .bind_addresses
+            .iter()
+            .any(|other| other.is_ipv4() && other.port() == address.port());
+        let listener = match bind(address, only_v6) {
+            Ok(listener) => Some(listener),
+            // A malformed address won't get any better by waiting
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Err(e).with_context(|| format!("Failed to bind to {address}"));
+            }
+            Err(e) => {
+                warn!("Failed to bind to {address}: {e}, retrying in the background");
+                None
+            }
+        };
+        listeners.push((address, only_v6, listener));
+    }
+    let admin = config
+        .admin_socket
+        .as_deref()
+        .map(bind_admin_socket)
+        .transpose()?;
+    let discovery = if config.discovery.enabled {
+        Some(discovery::bind(config.discovery.port).await?)
+    } else {
+        None
+    };
+    let announcement = discovery::Announcement {
+        server_id: discovery::load_server_id(&config.server_id_file)?,
+        api_port: config.bind_addresses[0].port(),
+        cert_fingerprint: tls.as_ref().map(|(_, fingerprint)| fingerprint.clone()),
+    };
 
-    let listener = tokio::net::TcpListener::bind(&config.bind_address)
-        .await
-        .with_context(|| format!("Failed to bind to {}", config.bind_address))?;
+    if let Some(user) = &config.user {
+        helper::drop_privileges(user)?;
+    }
 
-    info!("NAS Boot Server listening on {}", config.bind_address);
-    axum::serve(listener, app).await?;
+    if let Some((_, fingerprint)) = &tls {
+        info!("Serving HTTPS with certificate fingerprint {fingerprint}");
+    }
+
+    let mut servers = JoinSet::new();
+    for (address, only_v6, listener) in listeners {
+        let app = app.clone();
+        let acceptor = tls.as_ref().map(|(acceptor, _)| acceptor.clone());
+        let state = state.clone();
+        servers.spawn(async move {
+            let listener = match listener {
+                Some(listener) => listener,
+                None => bind_with_retry(address, only_v6).await?,
+            };
+            serve_api(listener, app, acceptor, &state).await
+        });
+    }
+
+    if let Some((listener, path)) = admin {
+        info!("Admin API listening on {path}");
+        let app = api::admin_router(state.clone());
+        servers.spawn(async move {
+            axum::serve(listener, app)
+                .await
+                .with_context(|| format!("Failed to serve on {path}"))
+        });
+    }
+
+    // Advertised for as long as the server runs
+    let _mdns = if config.mdns.enabled {
+        let (daemon, instance_name) =
+            mdns::advertise(&config.mdns, &announcement, &config.bind_addresses)?;
+        info!("Advertising {instance_name} over mDNS");
+        Some(daemon)
+    } else {
+        None
+    };
+
+    if let Some(socket) = discovery {
+        info!(
+            "Answering discovery probes on UDP port {} as server {}",
+            config.discovery.port, announcement.server_id
+        );
+        // Clients can still be configured by hand, so a failure only stops discovery
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = discovery::serve(socket, announcement, state).await {
+                error!("Stopped answering discovery probes: {e:#}");
+            }
+        });
+    }
+
+    // The servers only return on failure
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
 
     Ok(())
 }
 
-async fn handle_heartbeat(
-    state: axum::extract::State<AppState>,
-    Json(heartbeat): Json<Heartbeat>,
-) -> &'static str {
-    let mut clients = state.clients.lock().await;
-
-    match DateTime::parse_from_rfc3339(&heartbeat.timestamp) {
-        Ok(dt) => {
-            let hostname = heartbeat.hostname.clone();
-            clients.insert(heartbeat.hostname, dt.with_timezone(&Utc));
-            debug!("Heartbeat from {hostname}");
+/// Serve the API on `listener`, over HTTPS if there is an `acceptor`
+async fn serve_api(
+    listener: TcpListener,
+    app: axum::Router,
+    acceptor: Option<tokio_rustls::TlsAcceptor>,
+    state: &AppState,
+) -> Result<()> {
+    let address = listener.local_addr()?;
+    let config = &state.config;
+    let listener = GuardedListener::new(listener, &config.http, state.guard.clone());
+    let header_timeout = config.http.request_timeout;
+
+    match acceptor {
+        Some(acceptor) => {
+            let listener = TlsListener::new(
+                listener,
+                acceptor,
+                config.http.request_timeout,
+                state.guard.clone(),
+            )?;
+            info!("NAS Boot Server listening on https://{address}");
+            guard::serve(listener, app, header_timeout).await
+        }
+        None => {
+            info!("NAS Boot Server listening on {address}");
+            guard::serve(listener, app, header_timeout).await
         }
-        Err(e) => error!("Invalid timestamp: {e}"),
     }
+    .with_context(|| format!("Failed to serve on {address}"))
+}
 
-    "OK"
+/// Listen on the admin socket, accessible to root only
+fn bind_admin_socket(path: &str) -> Result<(UnixListener, String)> {
+    // Left behind if the server was killed
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path).with_context(|| format!("Failed to bind to {path}"))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {path}"))?;
+    Ok((listener, path.to_string()))
 }
 
-async fn shutdown_monitor(state: AppState) {
-    let mut interval = time::interval(Duration::from_secs(state.config.check_interval_secs));
-    let mut shutdown_timer: Option<DateTime<Utc>> = None;
+fn generate_cert(mut names: Vec<String>, force: bool) -> Result<()> {
+    if names.is_empty() {
+        names.push(mdns::hostname().unwrap_or_else(|| "localhost".to_string()));
+    }
 
-    loop {
-        interval.tick().await;
+    // Write the certificate where the configuration expects it, if it has a say
+    let tls = load_config()
+        .ok()
+        .and_then(|config| config.tls)
+        .unwrap_or_default();
+    tls::generate_cert(names, &tls, force)
+}
 
-        let now = Utc::now();
-        let mut active_clients = false;
+/// First and longest wait between attempts to bind the listener
+const BIND_RETRY_INITIAL: Duration = Duration::from_secs(1);
+const BIND_RETRY_MAX: Duration = Duration::from_secs(30);
 
-        {
-            let mut clients = state.clients.lock().await;
+/// Bind `address`, retrying with backoff while it isn't available yet, e.g.
+/// because the network is still coming up at boot. Waits before the first
+/// attempt, as the caller has tried already.
+async fn bind_with_retry(address: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+    let mut delay = BIND_RETRY_INITIAL;
 
-            clients.retain(|hostname, last_seen| {
-                let age = now.signed_duration_since(*last_seen);
-                if age.num_minutes() < state.config.heartbeat_timeout_mins {
-                    active_clients = true;
-                    true
-                } else {
-                    info!("Client {hostname} timed out");
-                    false
-                }
-            });
+    loop {
+        time::sleep(delay).await;
+        match bind(address, only_v6) {
+            Ok(listener) => return Ok(listener),
+            // A malformed address won't get any better by waiting
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                return Err(e).with_context(|| format!("Failed to bind to {address}"));
+            }
+            Err(e) => {
+                delay = (delay * 2).min(BIND_RETRY_MAX);
+                warn!(
+                    "Failed to bind to {address}: {e}, retrying in {}",
+                    humantime::format_duration(delay)
+                );
+            }
         }
+    }
+}
+
+/// Bind a TCP listener; an IPv6 one also accepts IPv4 unless `only_v6` is set
+fn bind(address: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Wait before restarting a monitor that panicked, so that a persistent
+/// fault doesn't turn into a busy loop
+const MONITOR_RESTART_DELAY: Duration = Duration::from_secs(5);
 
-        if active_clients {
-            if shutdown_timer.is_some() {
-                info!("Active clients detected, cancelling shutdown timer");
-                shutdown_timer = None;
+/// Run the shutdown monitor, restarting it whenever it panics. The power tiers
+/// it entered are left before the restart, as the new monitor starts out in none.
+async fn supervise_monitor(state: AppState) {
+    // Outside the restarts, as the watch never ends
+    inhibitors::watch_keepalive_file(Path::new(&state.config.keepalive_file), state.wake.clone());
+    let entered = Arc::new(Mutex::new(Vec::new()));
+
+    loop {
+        match tokio::spawn(shutdown_monitor(state.clone(), entered.clone())).await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => {
+                let panic = e.into_panic();
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(ToString::to_string)
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown cause".to_string());
+                error!(
+                    "Shutdown monitor panicked ({message}), restarting in {}",
+                    humantime::format_duration(MONITOR_RESTART_DELAY)
+                );
+                leave_power_tiers(&state, &entered).await;
+                time::sleep(MONITOR_RESTART_DELAY).await;
             }
-        } else {
-            match shutdown_timer {
-                None => {
-                    info!("No active clients, starting shutdown timer");
-                    shutdown_timer = Some(now);
-                }
-                Some(timer_start) => {
-                    let elapsed = now.signed_duration_since(timer_start);
-                    if elapsed.num_minutes() >= state.config.shutdown_delay_mins {
-                        if should_shutdown(&state.config) {
-                            info!("Shutdown timer expired, initiating shutdown");
-                            initiate_shutdown();
-                            break;
-                        }
-                        shutdown_timer = None;
-                    }
-                }
+            Err(e) => {
+                error!("Shutdown monitor stopped: {e}");
+                return;
             }
         }
     }
 }
 
-fn should_shutdown(config: &Config) -> bool {
-    // Check keepalive file
-    if Path::new(&config.keepalive_file).exists() {
-        info!("Keepalive file exists, not shutting down");
-        return false;
+/// Undo the power tiers in `entered`, last entered first
+async fn leave_power_tiers(state: &AppState, entered: &Mutex<Vec<PowerTier>>) {
+    let changes: Vec<_> = entered
+        .lock()
+        .await
+        .drain(..)
+        .rev()
+        .map(|tier| {
+            info!("Leaving power tier {} before the restart", tier.name);
+            TierChange::Leave(tier)
+        })
+        .collect();
+    if !changes.is_empty() {
+        power::apply_changes(changes, state.helper.clone()).await;
+    }
+}
+
+fn show_stats(days: Option<usize>, csv: Option<&Path>) -> Result<()> {
+    let config = load_config()?;
+    let stats = Stats::load(Path::new(&config.stats_file))?;
+    let report = stats.report(config.energy_model(), Local::now(), days);
+
+    print!("{}", report.to_table());
+
+    if let Some(csv_path) = csv {
+        fs::write(csv_path, report.to_csv())
+            .with_context(|| format!("Failed to write CSV to {}", csv_path.display()))?;
+        println!("\nExported statistics to: {}", csv_path.display());
     }
 
-    // Check for backup process
-    let output = Command::new("ps").arg("aux").output().unwrap_or_else(|_| {
-        error!("Failed to execute ps command");
-        std::process::Output {
-            stdout: Vec::new(),
-            stderr: Vec::new(),
-            status: std::process::ExitStatus::from_raw(1),
+    Ok(())
+}
+
+fn run_simulation(
+    trace: &Path,
+    config: Option<&Path>,
+    shutdown_delay: Option<Duration>,
+    boot_duration: Duration,
+) -> Result<()> {
+    let mut config = match config {
+        Some(path) => load_config_from(path)?,
+        None => load_config()?,
+    };
+    if let Some(shutdown_delay) = shutdown_delay {
+        config.shutdown_delay = shutdown_delay;
+    }
+
+    let trace = simulate::load_trace(trace)?;
+    simulate::simulate(Arc::new(config), &trace, boot_duration).print();
+
+    Ok(())
+}
+
+/// Longest time between two ticks, so that on-time is accounted to the right day
+pub const MAX_TICK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the statistics are saved; every write would spin up the disks
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Run the monitor whenever something happened: a client came or went, an
+/// inhibitor changed, a deadline passed, or an inhibitor needs polling.
+/// Keeps `entered` up to date with the power tiers it is in.
+async fn shutdown_monitor(state: AppState, entered: Arc<Mutex<Vec<PowerTier>>>) {
+    let mut monitor = Monitor::new(state.config.clone());
+    let mut inhibitors = SystemInhibitors::new(
+        state.config.clone(),
+        state.wake.clone(),
+        state.helper.clone(),
+    );
+    let poll_interval = inhibitors
+        .poll_interval()
+        .map_or(MAX_TICK_INTERVAL, |interval| {
+            interval.min(MAX_TICK_INTERVAL)
+        });
+    let started = Instant::now();
+    let mut next_poll = started;
+    let mut stats_saved = started;
+
+    loop {
+        let now = Instant::now();
+        if now >= next_poll {
+            next_poll = now + poll_interval;
+        }
+
+        let observation = Observation {
+            now,
+            wall_time: Local::now(),
+            uptime: system_uptime().unwrap_or_else(|| now.duration_since(started)),
+            power: *state.power.lock().await,
+        };
+
+        let (expired, clients) = {
+            let mut clients = state.clients.lock().await;
+            (monitor.expire_clients(now, &mut clients), clients.clone())
+        };
+        let outcome = {
+            let mut stats = state.stats.lock().await;
+            monitor.tick(&observation, &clients, &mut stats, &mut inhibitors)
+        };
+
+        for event in expired.iter().chain(&outcome.events) {
+            log::log!(event.level(), "{event}");
         }
-    });
 
-    if String::from_utf8_lossy(&output.stdout).contains(&config.backup_process_pattern) {
-        info!("Backup process running, not shutting down");
-        return false;
+        if !outcome.changes.is_empty() {
+            power::apply_changes(outcome.changes.clone(), state.helper.clone()).await;
+            let mut entered = entered.lock().await;
+            for change in &outcome.changes {
+                match change {
+                    TierChange::Enter(tier) => entered.push(tier.clone()),
+                    TierChange::Leave(tier) => entered.retain(|other| other.name != tier.name),
+                }
+            }
+        }
+
+        // Saving is put off while in a power tier, which may have spun down the disks
+        let save_due = outcome.status.power_tier.is_none()
+            && now.duration_since(stats_saved) >= STATS_SAVE_INTERVAL;
+        *state.status.lock().await = outcome.status;
+        if save_due || outcome.shutdown {
+            save_stats(&state).await;
+            stats_saved = now;
+        }
+
+        if outcome.shutdown {
+            power_off(&state).await;
+            // Only shutdowns that took effect count toward the daily cap
+            state
+                .stats
+                .lock()
+                .await
+                .forget_shutdown(observation.wall_time);
+            let failed = Event::ShutdownFailed;
+            log::log!(failed.level(), "{failed}");
+            // Tick right away, verifying the shutdown took a while
+            continue;
+        }
+
+        let deadline = outcome
+            .wake_at
+            .map_or(next_poll, |wake_at| wake_at.min(next_poll));
+        tokio::select! {
+            () = time::sleep_until(deadline) => {}
+            () = state.wake.notified() => debug!("Shutdown monitor woken up"),
+        }
     }
+}
 
-    true
+/// Try the shutdown commands, reporting the monitor as alive meanwhile, as
+/// verifying each of them takes a while
+async fn power_off(state: &AppState) {
+    let power_off = power::power_off(&state.config, state.helper.as_ref());
+    tokio::pin!(power_off);
+    let mut alive = time::interval(MAX_TICK_INTERVAL);
+    loop {
+        tokio::select! {
+            () = &mut power_off => return,
+            _ = alive.tick() => state.status.lock().await.last_tick = Some(Instant::now()),
+        }
+    }
 }
 
-fn initiate_shutdown() {
-    info!("Initiating system shutdown");
+/// Time since the system booted, read from `/proc/uptime`
+fn system_uptime() -> Option<Duration> {
+    let uptime = fs::read_to_string("/proc/uptime").ok()?;
+    let secs: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_secs_f64(secs))
+}
 
-    match Command::new("/sbin/poweroff").spawn() {
-        Ok(_) => info!("Shutdown command issued"),
-        Err(e) => error!("Failed to issue shutdown command: {e}"),
+/// Save the statistics, through the helper if the server dropped its privileges
+async fn save_stats(state: &AppState) {
+    let stats = state.stats.lock().await.clone();
+    let path = PathBuf::from(&state.config.stats_file);
+    let helper = state.helper.clone();
+    let saved = tokio::task::spawn_blocking(move || match helper {
+        Some(helper) => helper.request(&Request::SaveStats { stats }).map(drop),
+        None => stats.save(&path),
+    })
+    .await;
+
+    match saved {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Failed to save statistics: {e:#}"),
+        Err(e) => error!("Failed to save statistics: {e}"),
     }
 }
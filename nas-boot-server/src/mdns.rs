@@ -0,0 +1,170 @@
+//! Advertisement of the server over mDNS / DNS-SD, so that clients can find
+//! it by name when its address changes
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use nas_boot_protocol::{txt, NetworkInterface, API_VERSION, MDNS_SERVICE_TYPE};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::config::MdnsConfig;
+use crate::discovery::{self, Announcement};
+
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+
+/// A TXT property, key and value, is prefixed with a one-byte length
+const MAX_TXT_PROPERTY_BYTES: usize = 255;
+
+/// The host name of the NAS, which also names the service by default
+pub fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Register the service; it is advertised until the returned daemon is dropped
+pub fn advertise(
+    config: &MdnsConfig,
+    announcement: &Announcement,
+    bind_addresses: &[SocketAddr],
+) -> Result<(ServiceDaemon, String)> {
+    let host = hostname().unwrap_or_else(|| "nas-boot-server".to_string());
+    let instance_name = config.instance_name.clone().unwrap_or_else(|| host.clone());
+
+    // Advertise the addresses listened on, or all of them when listening everywhere
+    let addresses: Vec<IpAddr> = bind_addresses.iter().map(SocketAddr::ip).collect();
+    let listens_everywhere = addresses.iter().any(IpAddr::is_unspecified);
+
+    let interface = main_interface(&addresses);
+    match &interface {
+        Some(interface) => debug!("Advertising the MAC address of {}", interface.name),
+        None => {
+            warn!("Found no network interface clients reach the server through, advertising no MAC address")
+        }
+    }
+    let (mac, mac_addresses) = interface
+        .map(|interface| (interface.mac, join_addresses(&interface.addresses)))
+        .unwrap_or_default();
+    let properties = [
+        (txt::API_VERSION, API_VERSION.to_string()),
+        (txt::SERVER_ID, announcement.server_id.clone()),
+        (txt::MAC, mac),
+        (txt::MAC_ADDRESSES, mac_addresses),
+        (
+            txt::TLS,
+            u8::from(announcement.cert_fingerprint.is_some()).to_string(),
+        ),
+    ];
+
+    let service = ServiceInfo::new(
+        MDNS_SERVICE_TYPE,
+        &instance_name,
+        &format!("{host}.local."),
+        if listens_everywhere {
+            &[][..]
+        } else {
+            &addresses[..]
+        },
+        announcement.api_port,
+        &properties[..],
+    )
+    .context("Invalid mDNS service")?;
+    let service = if listens_everywhere {
+        service.enable_addr_auto()
+    } else {
+        service
+    };
+
+    let daemon = ServiceDaemon::new().context("Failed to start mDNS responder")?;
+    daemon
+        .register(service)
+        .context("Failed to register mDNS service")?;
+    Ok((daemon, instance_name))
+}
+
+/// The interface clients reach the server through: the one with an address it
+/// listens on, or the one with the default route when it listens everywhere.
+/// Bridges such as docker0 sort before the physical interfaces, so the order
+/// of the interfaces doesn't tell.
+fn main_interface(addresses: &[IpAddr]) -> Option<NetworkInterface> {
+    let interfaces = discovery::network_interfaces();
+    if addresses.iter().any(IpAddr::is_unspecified) {
+        let name = default_route_interface()?;
+        interfaces
+            .into_iter()
+            .find(|interface| interface.name == name)
+    } else {
+        interfaces.into_iter().find(|interface| {
+            addresses
+                .iter()
+                .any(|address| interface.addresses.contains(&address.to_canonical()))
+        })
+    }
+}
+
+/// `addresses` separated by commas, as many as fit in a TXT property
+fn join_addresses(addresses: &[IpAddr]) -> String {
+    let room = MAX_TXT_PROPERTY_BYTES - txt::MAC_ADDRESSES.len() - "=".len();
+    let mut joined = String::new();
+    for address in addresses {
+        let address = address.to_string();
+        let separator = if joined.is_empty() { "" } else { "," };
+        if joined.len() + separator.len() + address.len() > room {
+            break;
+        }
+        joined.push_str(separator);
+        joined.push_str(&address);
+    }
+    joined
+}
+
+/// Name of the interface with the IPv4 default route
+fn default_route_interface() -> Option<String> {
+    let routes = std::fs::read_to_string(PROC_NET_ROUTE)
+        .inspect_err(|e| warn!("Failed to read {PROC_NET_ROUTE}: {e}"))
+        .ok()?;
+    default_route(&routes).map(str::to_string)
+}
+
+/// The interface of the default route in the contents of /proc/net/route
+fn default_route(routes: &str) -> Option<&str> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Iface, Destination, Gateway, Flags, RefCnt, Use, Metric, Mask, ...
+        match fields.as_slice() {
+            [name, "00000000", _, _, _, _, _, "00000000", ..] => Some(*name),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_default_route() {
+        let routes = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+docker0\t000011AC\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+        assert_eq!(default_route(routes), Some("eth0"));
+        assert_eq!(default_route(routes.lines().next().unwrap()), None);
+    }
+
+    #[test]
+    fn joins_addresses_that_fit() {
+        let addresses: Vec<IpAddr> = std::iter::once("192.168.1.10".parse().unwrap())
+            .chain((0..10).map(|i| format!("fd00::1234:5678:9abc:{i}").parse().unwrap()))
+            .collect();
+        let joined = join_addresses(&addresses);
+        assert!(joined.starts_with("192.168.1.10,fd00::1234:5678:9abc:0,"));
+        assert!(txt::MAC_ADDRESSES.len() + 1 + joined.len() <= MAX_TXT_PROPERTY_BYTES);
+        assert!(joined
+            .split(',')
+            .all(|address| address.parse::<IpAddr>().is_ok()));
+    }
+}
@@ -0,0 +1,647 @@
+use chrono::{DateTime, Local, Utc};
+use humantime::format_duration;
+use log::Level;
+use nas_boot_protocol::TimerState;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::{Config, PowerAction, PowerTier};
+use crate::stats::Stats;
+use crate::ups::PowerStatus;
+use crate::ClientInfo;
+
+/// Source of shutdown inhibitors, such as the keepalive file or a running backup
+pub trait Inhibitors {
+    /// Take periodic measurements; called on every tick
+    fn sample(&mut self, _now: Instant) {}
+
+    /// How often to tick for the sake of the inhibitors, if they need polling
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The first inhibitor that currently prevents a shutdown, if any
+    fn active_inhibitor(&mut self, now: Instant) -> Option<Inhibition>;
+}
+
+/// An inhibitor that keeps the NAS on, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inhibition {
+    /// Inhibitor name, used for statistics
+    pub name: String,
+    /// Human-readable explanation, e.g. "keepalive file exists"
+    pub reason: String,
+}
+
+/// Inputs of a single monitor tick, provided by the caller so the decision
+/// logic can run against the real clock as well as a simulated one
+#[derive(Debug, Clone, Copy)]
+pub struct Observation {
+    /// Monotonic time of the tick
+    pub now: Instant,
+    /// Wall-clock time of the tick, used for per-day accounting and reporting
+    pub wall_time: DateTime<Local>,
+    /// Time since the system booted
+    pub uptime: Duration,
+    pub power: PowerStatus,
+}
+
+/// Something noteworthy that happened during a tick
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    ClientTimedOut(String),
+    TimerStarted,
+    TimerCancelled,
+    BootGrace,
+    WaitingForUptime(Duration),
+    Inhibited(Inhibition),
+    CycleLimitReached(u32),
+    TierEntered(String),
+    TierLeft(String),
+    /// The timer wasn't started because a power outage may still be brief
+    OutageGrace,
+    /// Battery is too low to keep the NAS on for clients
+    IgnoringClients,
+    /// The shutdown timer expired without any inhibitor; `idle_for` is the time since it started
+    Shutdown {
+        idle_for: Duration,
+    },
+    /// The NAS was still up after every shutdown command had been tried
+    ShutdownFailed,
+}
+
+impl Event {
+    pub fn level(&self) -> Level {
+        match self {
+            Self::BootGrace | Self::WaitingForUptime(_) | Self::OutageGrace => Level::Debug,
+            Self::CycleLimitReached(_) | Self::IgnoringClients => Level::Warn,
+            Self::ShutdownFailed => Level::Error,
+            _ => Level::Info,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ClientTimedOut(hostname) => write!(f, "Client {hostname} timed out"),
+            Self::TimerStarted => write!(f, "No active clients, starting shutdown timer"),
+            Self::TimerCancelled => write!(f, "Active clients detected, cancelling shutdown timer"),
+            Self::BootGrace => write!(f, "No active clients, but still within boot grace period"),
+            Self::WaitingForUptime(min_uptime) => write!(
+                f,
+                "Shutdown timer expired, waiting for minimum uptime of {}",
+                format_duration(*min_uptime)
+            ),
+            Self::Inhibited(inhibition) => {
+                write!(f, "Not shutting down: {}", inhibition.reason)
+            }
+            Self::CycleLimitReached(limit) => write!(
+                f,
+                "Reached {limit} power cycles today, staying on until tomorrow"
+            ),
+            Self::OutageGrace => write!(
+                f,
+                "No active clients, but not starting shutdown timer during a brief power outage"
+            ),
+            Self::IgnoringClients => {
+                write!(f, "Battery is low, ignoring heartbeats from active clients")
+            }
+            Self::TierEntered(tier) => write!(f, "No active clients, entering power tier {tier}"),
+            Self::TierLeft(tier) => write!(f, "Active clients detected, leaving power tier {tier}"),
+            Self::Shutdown { .. } => write!(f, "Shutdown timer expired, initiating shutdown"),
+            Self::ShutdownFailed => write!(
+                f,
+                "NAS is still up after trying all shutdown commands, resuming monitoring"
+            ),
+        }
+    }
+}
+
+/// State of the monitor as reported through the API
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MonitorStatus {
+    pub timer: TimerState,
+    /// Name of the power tier entered last, if any
+    pub power_tier: Option<String>,
+    /// When the monitor last ran, to tell whether it is still alive
+    pub last_tick: Option<Instant>,
+}
+
+/// A power tier to enter or to leave again by undoing its action
+#[derive(Debug, Clone, PartialEq)]
+pub enum TierChange {
+    Enter(PowerTier),
+    Leave(PowerTier),
+}
+
+/// Result of a single monitor tick
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub events: Vec<Event>,
+    pub status: MonitorStatus,
+    /// Power tier actions to run, in order
+    pub changes: Vec<TierChange>,
+    /// Whether the NAS should be powered off now
+    pub shutdown: bool,
+    /// When the next tick is due at the latest, if a deadline is pending
+    pub wake_at: Option<Instant>,
+}
+
+/// Shutdown decision logic, free of any clock, process or power-off side effects
+pub struct Monitor {
+    config: Arc<Config>,
+    shutdown_timer: Option<Instant>,
+    last_tick: Option<Instant>,
+    cycle_limit_warned: bool,
+    ignoring_clients: bool,
+    tiers: Vec<PowerTier>,
+    /// Number of tiers entered since clients were last active
+    tier: usize,
+    /// Whether entering the next tier is held back by an inhibitor
+    tier_inhibited: bool,
+}
+
+impl Monitor {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self {
+            shutdown_timer: None,
+            last_tick: None,
+            cycle_limit_warned: false,
+            ignoring_clients: false,
+            tiers: config.effective_power_tiers(),
+            tier: 0,
+            tier_inhibited: false,
+            config,
+        }
+    }
+
+    /// Forget the clients whose last heartbeat is older than `heartbeat_timeout`.
+    ///
+    /// Meant to run under the lock of the client list, which `tick` then only
+    /// gets a snapshot of, so that heartbeats don't wait for the inhibitors.
+    pub fn expire_clients(
+        &self,
+        now: Instant,
+        clients: &mut HashMap<String, ClientInfo>,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        clients.retain(|hostname, client| {
+            let alive = self.is_alive(now, client);
+            if !alive {
+                events.push(Event::ClientTimedOut(hostname.clone()));
+            }
+            alive
+        });
+        events
+    }
+
+    fn is_alive(&self, now: Instant, client: &ClientInfo) -> bool {
+        now.saturating_duration_since(client.last_seen) < self.config.heartbeat_timeout
+    }
+
+    /// Decide what to do with `clients` as of `observation`; expired clients
+    /// are expected to be removed by `expire_clients` beforehand
+    pub fn tick(
+        &mut self,
+        observation: &Observation,
+        clients: &HashMap<String, ClientInfo>,
+        stats: &mut Stats,
+        inhibitors: &mut dyn Inhibitors,
+    ) -> Outcome {
+        let config = &self.config;
+        let now = observation.now;
+        let mut events = Vec::new();
+        let mut shutdown = false;
+        let mut changes = Vec::new();
+
+        inhibitors.sample(now);
+
+        let active_clients: Vec<String> = clients
+            .iter()
+            .filter(|(_, client)| self.is_alive(now, client))
+            .map(|(hostname, _)| hostname.clone())
+            .collect();
+
+        // On battery, the UPS settings take over once the outage outlasts its grace period
+        let ups = config.ups.as_ref();
+        let outage = observation.power.outage(now);
+        let brief_outage = outage
+            .zip(ups.and_then(|ups| ups.outage_grace))
+            .is_some_and(|(outage, grace)| outage < grace);
+        let on_battery = ups.filter(|_| outage.is_some() && !brief_outage);
+        // On battery, every remaining tier is due once the shorter delay has passed
+        let tier_delay = |tier: &PowerTier| match on_battery {
+            Some(ups) => tier.delay.min(ups.on_battery_shutdown_delay),
+            None => tier.delay,
+        };
+
+        let ignore_clients = on_battery.is_some_and(|ups| {
+            let below_minimum = ups
+                .min_battery_for_clients
+                .zip(observation.power.battery_charge)
+                .is_some_and(|(minimum, charge)| charge < minimum);
+            below_minimum || observation.power.low_battery
+        });
+        if ignore_clients && !active_clients.is_empty() && !self.ignoring_clients {
+            events.push(Event::IgnoringClients);
+        }
+        self.ignoring_clients = ignore_clients && !active_clients.is_empty();
+
+        // Account the time since the previous tick as on-time
+        if let Some(previous) = self.last_tick {
+            let secs = now.duration_since(previous).as_secs_f64().round() as u64;
+            stats.record_uptime(observation.wall_time, secs, &active_clients);
+        }
+        self.last_tick = Some(now);
+
+        if !active_clients.is_empty() && !ignore_clients {
+            if self.shutdown_timer.is_some() {
+                events.push(Event::TimerCancelled);
+                self.shutdown_timer = None;
+            }
+
+            // Undo the tiers in reverse order
+            for tier in self.tiers[..self.tier].iter().rev() {
+                events.push(Event::TierLeft(tier.name.clone()));
+                changes.push(TierChange::Leave(tier.clone()));
+            }
+            self.tier = 0;
+            self.tier_inhibited = false;
+        } else {
+            match self.shutdown_timer {
+                None if observation.uptime < config.boot_grace_period => {
+                    events.push(Event::BootGrace);
+                }
+                None if brief_outage => {
+                    events.push(Event::OutageGrace);
+                }
+                None => {
+                    events.push(Event::TimerStarted);
+                    self.shutdown_timer = Some(now);
+                }
+                Some(timer_start) => {
+                    let idle_for = now.duration_since(timer_start);
+                    while let Some(tier) = self.tiers.get(self.tier).cloned() {
+                        if idle_for < tier_delay(&tier) {
+                            break;
+                        }
+
+                        if tier.action != PowerAction::Shutdown {
+                            match inhibitors.active_inhibitor(now) {
+                                Some(inhibition) => {
+                                    // Retry on the next tick without restarting the timer
+                                    if !self.tier_inhibited {
+                                        stats.record_inhibitor(
+                                            observation.wall_time,
+                                            &inhibition.name,
+                                        );
+                                        events.push(Event::Inhibited(inhibition));
+                                        self.tier_inhibited = true;
+                                    }
+                                    break;
+                                }
+                                None => {
+                                    events.push(Event::TierEntered(tier.name.clone()));
+                                    changes.push(TierChange::Enter(tier));
+                                    self.tier += 1;
+                                    self.tier_inhibited = false;
+                                    continue;
+                                }
+                            }
+                        }
+
+                        if observation.uptime < config.min_uptime && on_battery.is_none() {
+                            // Keep the timer armed until the minimum uptime is reached
+                            events.push(Event::WaitingForUptime(config.min_uptime));
+                            break;
+                        }
+
+                        let limit = config.max_power_cycles_per_day;
+                        match inhibitors.active_inhibitor(now) {
+                            None if limit > 0
+                                && on_battery.is_none()
+                                && stats.shutdowns_on(observation.wall_time) >= limit =>
+                            {
+                                if !self.cycle_limit_warned {
+                                    events.push(Event::CycleLimitReached(limit));
+                                    self.cycle_limit_warned = true;
+                                }
+                            }
+                            None => {
+                                events.push(Event::Shutdown { idle_for });
+                                stats.record_shutdown(observation.wall_time);
+                                shutdown = true;
+                            }
+                            Some(inhibition) => {
+                                stats.record_inhibitor(observation.wall_time, &inhibition.name);
+                                events.push(Event::Inhibited(inhibition));
+                            }
+                        }
+                        // Start over, keeping the tiers entered so far
+                        self.shutdown_timer = None;
+                        break;
+                    }
+                }
+            }
+        }
+
+        let shutdown_tier = self
+            .tiers
+            .iter()
+            .find(|tier| tier.action == PowerAction::Shutdown);
+        let timer = match (self.shutdown_timer, shutdown_tier) {
+            (Some(timer_start), Some(shutdown_tier)) => {
+                let remaining =
+                    tier_delay(shutdown_tier).saturating_sub(now.duration_since(timer_start));
+                if remaining.is_zero() {
+                    TimerState::Held
+                } else {
+                    let remaining = chrono::Duration::from_std(remaining).unwrap_or_default();
+                    TimerState::Running {
+                        shutdown_at: observation.wall_time.with_timezone(&Utc) + remaining,
+                    }
+                }
+            }
+            (None, _)
+                if active_clients.is_empty() && observation.uptime < config.boot_grace_period =>
+            {
+                TimerState::BootGrace
+            }
+            _ => TimerState::Stopped,
+        };
+
+        // The earliest moment at which the outcome of a tick could change
+        let mut deadlines: Vec<Instant> = clients
+            .values()
+            .map(|client| client.last_seen + config.heartbeat_timeout)
+            .collect();
+        if let (Some(timer_start), Some(tier)) = (self.shutdown_timer, self.tiers.get(self.tier)) {
+            deadlines.push(timer_start + tier_delay(tier));
+        }
+        let held_back =
+            self.shutdown_timer.is_none() && (active_clients.is_empty() || ignore_clients);
+        if self.tier_inhibited || held_back {
+            // Inhibitors are polled while they hold back the next tier or the shutdown
+            deadlines.push(now + config.check_interval);
+        }
+        if active_clients.is_empty() || ignore_clients {
+            for after_boot in [config.boot_grace_period, config.min_uptime] {
+                if let Some(remaining) = after_boot.checked_sub(observation.uptime) {
+                    deadlines.push(now + remaining);
+                }
+            }
+        }
+        if let (true, Some(since), Some(grace)) = (
+            brief_outage,
+            observation.power.on_battery_since,
+            ups.and_then(|ups| ups.outage_grace),
+        ) {
+            deadlines.push(since + grace);
+        }
+        let wake_at = deadlines
+            .into_iter()
+            .filter(|deadline| *deadline > now)
+            .min();
+
+        let status = MonitorStatus {
+            timer,
+            power_tier: self
+                .tier
+                .checked_sub(1)
+                .map(|entered| self.tiers[entered].name.clone()),
+            last_tick: Some(now),
+        };
+
+        Outcome {
+            events,
+            status,
+            changes,
+            shutdown,
+            wake_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpsConfig;
+    use chrono::TimeZone;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    /// A single inhibitor that is either active or not on a tick
+    struct Backup(bool);
+
+    impl Inhibitors for Backup {
+        fn active_inhibitor(&mut self, _now: Instant) -> Option<Inhibition> {
+            self.0.then(backup_running)
+        }
+    }
+
+    fn backup_running() -> Inhibition {
+        Inhibition {
+            name: "backup_process".to_string(),
+            reason: "backup process is running".to_string(),
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        config: Config,
+        /// System uptime at minute 0
+        uptime: Duration,
+        power: PowerStatus,
+        /// Shutdowns already initiated today
+        shutdowns_today: u32,
+        /// Minute of the only heartbeat, from client `desktop`
+        heartbeat: Option<u32>,
+        /// Minute of each tick, whether the backup runs meanwhile, and the events expected
+        ticks: Vec<(u32, bool, Vec<Event>)>,
+    }
+
+    /// A config without boot grace and minimum uptime, so cases enable only what they test
+    fn config() -> Config {
+        Config {
+            shutdown_delay: 10 * MINUTE,
+            heartbeat_timeout: 2 * MINUTE,
+            boot_grace_period: Duration::ZERO,
+            min_uptime: Duration::ZERO,
+            max_power_cycles_per_day: 6,
+            ..Config::default()
+        }
+    }
+
+    fn case(name: &'static str, config: Config) -> Case {
+        Case {
+            name,
+            config,
+            uptime: 60 * MINUTE,
+            power: PowerStatus::default(),
+            shutdowns_today: 0,
+            heartbeat: None,
+            ticks: Vec::new(),
+        }
+    }
+
+    fn shutdown(idle_minutes: u32) -> Event {
+        Event::Shutdown {
+            idle_for: idle_minutes * MINUTE,
+        }
+    }
+
+    fn cases(start: Instant) -> Vec<Case> {
+        vec![
+            Case {
+                heartbeat: Some(0),
+                ticks: vec![
+                    (1, false, vec![]),
+                    (
+                        2,
+                        false,
+                        vec![
+                            Event::ClientTimedOut("desktop".to_string()),
+                            Event::TimerStarted,
+                        ],
+                    ),
+                    (12, false, vec![shutdown(10)]),
+                ],
+                ..case("heartbeat expiry", config())
+            },
+            Case {
+                ticks: vec![
+                    (0, false, vec![Event::TimerStarted]),
+                    (10, true, vec![Event::Inhibited(backup_running())]),
+                    // The inhibitor restarts the timer
+                    (11, false, vec![Event::TimerStarted]),
+                    (20, false, vec![]),
+                    (21, false, vec![shutdown(10)]),
+                ],
+                ..case("inhibitor blocks an expired timer", config())
+            },
+            Case {
+                uptime: 15 * MINUTE,
+                ticks: vec![
+                    (0, false, vec![Event::TimerStarted]),
+                    (10, false, vec![Event::WaitingForUptime(30 * MINUTE)]),
+                    // The timer stays armed meanwhile
+                    (15, false, vec![shutdown(15)]),
+                ],
+                ..case(
+                    "minimum uptime defers the shutdown",
+                    Config {
+                        min_uptime: 30 * MINUTE,
+                        ..config()
+                    },
+                )
+            },
+            Case {
+                uptime: Duration::ZERO,
+                ticks: vec![
+                    (0, false, vec![Event::BootGrace]),
+                    (9, false, vec![Event::BootGrace]),
+                    (10, false, vec![Event::TimerStarted]),
+                    (20, false, vec![shutdown(10)]),
+                ],
+                ..case(
+                    "boot grace defers the timer",
+                    Config {
+                        boot_grace_period: 10 * MINUTE,
+                        ..config()
+                    },
+                )
+            },
+            Case {
+                shutdowns_today: 6,
+                ticks: vec![
+                    (0, false, vec![Event::TimerStarted]),
+                    (10, false, vec![Event::CycleLimitReached(6)]),
+                    (11, false, vec![Event::TimerStarted]),
+                    // Warned once a day only
+                    (21, false, vec![]),
+                ],
+                ..case("daily cycle cap", config())
+            },
+            Case {
+                shutdowns_today: 6,
+                power: PowerStatus {
+                    on_battery_since: Some(start),
+                    battery_charge: Some(80.0),
+                    low_battery: false,
+                },
+                ticks: vec![
+                    (0, false, vec![Event::TimerStarted]),
+                    // Neither the cap nor the longer delay hold on battery
+                    (5, false, vec![shutdown(5)]),
+                ],
+                ..case(
+                    "on battery, the UPS delay applies",
+                    Config {
+                        ups: Some(UpsConfig {
+                            host: "localhost".to_string(),
+                            port: 3493,
+                            name: "qnapups".to_string(),
+                            poll_interval: Duration::from_secs(5),
+                            on_battery_shutdown_delay: 5 * MINUTE,
+                            min_battery_for_clients: None,
+                            outage_grace: None,
+                        }),
+                        ..config()
+                    },
+                )
+            },
+        ]
+    }
+
+    #[test]
+    fn ticks_through_the_shutdown_decisions() {
+        let start = Instant::now();
+        let wall_start = Local.with_ymd_and_hms(2025, 1, 15, 12, 0, 0).unwrap();
+
+        for case in cases(start) {
+            let mut monitor = Monitor::new(Arc::new(case.config));
+            let mut stats = Stats::default();
+            for _ in 0..case.shutdowns_today {
+                stats.record_shutdown(wall_start);
+            }
+            let mut clients = HashMap::new();
+            if let Some(minute) = case.heartbeat {
+                clients.insert(
+                    "desktop".to_string(),
+                    ClientInfo {
+                        last_seen: start + minute * MINUTE,
+                        clock_skew: None,
+                        protocol_version: 0,
+                        capabilities: Vec::new(),
+                    },
+                );
+            }
+
+            for (minute, backup, expected) in case.ticks {
+                let elapsed = minute * MINUTE;
+                let observation = Observation {
+                    now: start + elapsed,
+                    wall_time: wall_start + chrono::Duration::minutes(i64::from(minute)),
+                    uptime: case.uptime + elapsed,
+                    power: case.power,
+                };
+
+                let mut events = monitor.expire_clients(observation.now, &mut clients);
+                let outcome = monitor.tick(&observation, &clients, &mut stats, &mut Backup(backup));
+                events.extend(outcome.events);
+
+                assert_eq!(events, expected, "{}, minute {minute}", case.name);
+                assert_eq!(
+                    outcome.shutdown,
+                    matches!(expected.last(), Some(Event::Shutdown { .. })),
+                    "{}, minute {minute}",
+                    case.name
+                );
+            }
+        }
+    }
+}
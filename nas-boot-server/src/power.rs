@@ -0,0 +1,138 @@
+//! Actions of the power tiers and the shutdown itself, run by the shutdown monitor
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+use crate::config::{Config, PowerAction};
+use crate::helper::{Helper, Request};
+use crate::monitor::TierChange;
+
+/// Longest a shutdown command may take to return
+const SHUTDOWN_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Run the action of a tier being entered, or its undo action when leaving it
+pub fn apply(change: &TierChange) {
+    match change {
+        TierChange::Enter(tier) => match &tier.action {
+            PowerAction::DiskStandby { disks } => {
+                for disk in disks {
+                    run(Command::new("hdparm").arg("-y").arg(disk));
+                }
+            }
+            PowerAction::StopService { service } => run(Command::new(service).arg("stop")),
+            PowerAction::Command { command, .. } => run(Command::new("sh").arg("-c").arg(command)),
+            // Handled by the monitor, after the anti-flapping checks
+            PowerAction::Shutdown => {}
+        },
+        TierChange::Leave(tier) => match &tier.action {
+            PowerAction::StopService { service } => run(Command::new(service).arg("start")),
+            PowerAction::Command {
+                undo_command: Some(undo_command),
+                ..
+            } => run(Command::new("sh").arg("-c").arg(undo_command)),
+            // Disks spin up again by themselves on the next access
+            _ => {}
+        },
+    }
+}
+
+/// Try the configured shutdown commands in order until one takes effect.
+///
+/// A command counts as failed if it doesn't exit successfully, or if this
+/// process is still running `shutdown_verify_timeout` after it did. Returns
+/// only if every command failed.
+///
+/// The commands run in the privileged helper if the server dropped its
+/// privileges.
+pub async fn power_off(config: &Config, helper: Option<&Arc<Helper>>) {
+    for (index, command) in config.shutdown_commands.iter().enumerate() {
+        info!("Running shutdown command '{command}'");
+
+        let result = match helper {
+            Some(helper) => {
+                let helper = helper.clone();
+                tokio::task::spawn_blocking(move || helper.request(&Request::Shutdown { index }))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|response| response.map(drop))
+            }
+            None => run_shutdown_command(command).await,
+        };
+        if let Err(e) = result {
+            warn!("Shutdown command '{command}' failed: {e:#}");
+            continue;
+        }
+
+        time::sleep(config.shutdown_verify_timeout).await;
+        warn!(
+            "NAS is still up {} after '{command}'",
+            humantime::format_duration(config.shutdown_verify_timeout)
+        );
+    }
+}
+
+/// Run a single shutdown command and wait for it to return
+pub async fn run_shutdown_command(command: &str) -> Result<()> {
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .status();
+    let status = time::timeout(SHUTDOWN_COMMAND_TIMEOUT, status)
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "did not return within {}",
+                humantime::format_duration(SHUTDOWN_COMMAND_TIMEOUT)
+            )
+        })?
+        .context("failed to run")?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("exited with {status}"))
+    }
+}
+
+/// Apply tier changes in order, through `helper` if the server dropped its privileges
+pub async fn apply_changes(changes: Vec<TierChange>, helper: Option<Arc<Helper>>) {
+    let applied = tokio::task::spawn_blocking(move || {
+        for change in &changes {
+            let Some(helper) = &helper else {
+                apply(change);
+                continue;
+            };
+
+            let (tier, enter) = match change {
+                TierChange::Enter(tier) => (tier, true),
+                TierChange::Leave(tier) => (tier, false),
+            };
+            let request = Request::PowerTier {
+                name: tier.name.clone(),
+                enter,
+            };
+            if let Err(e) = helper.request(&request) {
+                error!("Failed to apply power tier {}: {e:#}", tier.name);
+            }
+        }
+    });
+
+    if let Err(e) = applied.await {
+        error!("Power tier action failed: {e}");
+    }
+}
+
+fn run(command: &mut Command) {
+    let description = format!("{command:?}");
+    match command.status() {
+        Ok(status) if status.success() => info!("Ran {description}"),
+        Ok(status) => warn!("{description} failed with {status}"),
+        Err(e) => error!("Failed to run {description}: {e}"),
+    }
+}
@@ -0,0 +1,446 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use humantime::format_duration;
+use log::Level;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::monitor::{Event, Inhibition, Inhibitors, Monitor, Observation};
+use crate::stats::Stats;
+use crate::ups::PowerStatus;
+use crate::{ClientInfo, MAX_TICK_INTERVAL};
+
+/// A recorded event to replay against the monitor
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// The NAS was powered on, e.g. by a script sending Wake-on-LAN
+    Boot,
+    Heartbeat {
+        hostname: String,
+    },
+    Inhibitor {
+        name: String,
+        active: bool,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TraceEntry {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+/// Load a trace from a JSON array of entries or from CSV with the columns
+/// `timestamp,event,name,active`
+pub fn load_trace(path: &Path) -> Result<Vec<TraceEntry>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read trace from {}", path.display()))?;
+
+    let mut trace = if content.trim_start().starts_with('[') {
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse trace from {}", path.display()))?
+    } else {
+        parse_csv(&content)
+            .with_context(|| format!("Failed to parse trace from {}", path.display()))?
+    };
+
+    trace.sort_by_key(|entry: &TraceEntry| entry.timestamp);
+    Ok(trace)
+}
+
+fn parse_csv(content: &str) -> Result<Vec<TraceEntry>> {
+    let mut trace = Vec::new();
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("timestamp") {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or_default();
+
+        let timestamp = DateTime::parse_from_rfc3339(field(0))
+            .with_context(|| format!("Invalid timestamp on line {}", index + 1))?
+            .with_timezone(&Utc);
+
+        let event = match field(1) {
+            "boot" => TraceEvent::Boot,
+            "heartbeat" => TraceEvent::Heartbeat {
+                hostname: field(2).to_string(),
+            },
+            "inhibitor" => TraceEvent::Inhibitor {
+                name: field(2).to_string(),
+                active: matches!(field(3), "true" | "1" | "on" | "yes"),
+            },
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Unknown event '{other}' on line {}",
+                    index + 1
+                ))
+            }
+        };
+
+        trace.push(TraceEntry { timestamp, event });
+    }
+
+    Ok(trace)
+}
+
+#[derive(Default)]
+struct SimulatedInhibitors {
+    active: BTreeMap<String, bool>,
+}
+
+impl Inhibitors for SimulatedInhibitors {
+    fn active_inhibitor(&mut self, _now: Instant) -> Option<Inhibition> {
+        self.active
+            .iter()
+            .find(|(_, active)| **active)
+            .map(|(name, _)| Inhibition {
+                name: name.clone(),
+                reason: format!("{name} is active"),
+            })
+    }
+}
+
+/// A simulated power-off and the reason for it
+#[derive(Debug, Clone)]
+pub struct PowerOff {
+    pub at: DateTime<Utc>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulationReport {
+    /// Noteworthy monitor events in chronological order
+    pub timeline: Vec<(DateTime<Utc>, String)>,
+    pub power_offs: Vec<PowerOff>,
+    /// Time the NAS was on within the traced period
+    pub on_time: Duration,
+    pub traced: Duration,
+}
+
+/// Whether the simulated NAS is powered on, and since when
+#[derive(Debug, Clone, Copy)]
+enum Power {
+    Off,
+    On {
+        since: DateTime<Utc>,
+        ready_at: DateTime<Utc>,
+    },
+}
+
+/// Replay `trace` against the shutdown monitor on a virtual clock.
+///
+/// The NAS is assumed to be up at the start of the trace. While it is off,
+/// a heartbeat stands for the client's Wake-on-LAN and powers it on again;
+/// heartbeats that arrive while it is still booting are lost.
+pub fn simulate(
+    config: Arc<Config>,
+    trace: &[TraceEntry],
+    boot_duration: Duration,
+) -> SimulationReport {
+    let mut report = SimulationReport {
+        timeline: Vec::new(),
+        power_offs: Vec::new(),
+        on_time: Duration::ZERO,
+        traced: Duration::ZERO,
+    };
+
+    let (Some(first), Some(last)) = (trace.first(), trace.last()) else {
+        return report;
+    };
+    let start = first.timestamp;
+    let end = last.timestamp;
+    report.traced = (end - start).to_std().unwrap_or_default();
+
+    // Keep ticking after the last entry until any pending shutdown has played out
+    let longest_delay = config
+        .effective_power_tiers()
+        .iter()
+        .map(|tier| tier.delay)
+        .max()
+        .unwrap_or_default();
+    let horizon = end
+        + to_chrono(
+            config.heartbeat_timeout
+                + longest_delay
+                + config.min_uptime
+                + config.boot_grace_period
+                + boot_duration
+                + 2 * config.check_interval,
+        );
+
+    let base = Instant::now();
+    let to_instant = |time: DateTime<Utc>| base + (time - start).to_std().unwrap_or_default();
+    let poll_interval = to_chrono(MAX_TICK_INTERVAL);
+
+    let mut monitor = Monitor::new(config.clone());
+    let mut clients: HashMap<String, ClientInfo> = HashMap::new();
+    let mut stats = Stats::default();
+    let mut inhibitors = SimulatedInhibitors::default();
+    let mut last_heartbeat: Option<(String, DateTime<Utc>)> = None;
+
+    let mut power = Power::On {
+        since: start,
+        ready_at: start,
+    };
+    let mut next_tick = start;
+    let mut next_poll = start;
+    let mut entries = trace.iter().peekable();
+
+    loop {
+        let entry_due = match (entries.peek(), power) {
+            (Some(entry), Power::On { .. }) => entry.timestamp <= next_tick,
+            (Some(_), Power::Off) => true,
+            (None, _) => false,
+        };
+
+        if entry_due {
+            let Some(entry) = entries.next() else { break };
+            let time = entry.timestamp;
+
+            match (&entry.event, power) {
+                (TraceEvent::Inhibitor { name, active }, _) => {
+                    let previous = inhibitors.active.insert(name.clone(), *active);
+                    // A change wakes the running monitor, like a background probe does
+                    let running = matches!(power, Power::On { ready_at, .. } if time >= ready_at);
+                    if running && previous.unwrap_or_default() != *active {
+                        next_tick = next_tick.min(time);
+                    }
+                }
+                (TraceEvent::Boot, Power::Off) => {
+                    report.timeline.push((time, "NAS powered on".to_string()));
+                    power = power_on(time, boot_duration, &mut next_tick);
+                }
+                (TraceEvent::Heartbeat { hostname }, Power::Off) => {
+                    report
+                        .timeline
+                        .push((time, format!("Heartbeat from {hostname} wakes the NAS")));
+                    power = power_on(time, boot_duration, &mut next_tick);
+                }
+                (TraceEvent::Heartbeat { hostname }, Power::On { ready_at, .. }) => {
+                    if time >= ready_at {
+                        let known = clients.insert(
+                            hostname.clone(),
+                            ClientInfo {
+                                last_seen: to_instant(time),
+                                clock_skew: None,
+                                protocol_version: 0,
+                                capabilities: Vec::new(),
+                            },
+                        );
+                        last_heartbeat = Some((hostname.clone(), time));
+                        // A new or returning client wakes the monitor
+                        if known.is_none() {
+                            next_tick = next_tick.min(time);
+                        }
+                    }
+                }
+                (TraceEvent::Boot, Power::On { .. }) => {}
+            }
+            continue;
+        }
+
+        let Power::On { since, .. } = power else {
+            break;
+        };
+        if next_tick > horizon {
+            report.on_time += clipped(since, horizon, start, end);
+            break;
+        }
+
+        let time = next_tick;
+        if time >= next_poll {
+            next_poll = time + poll_interval;
+        }
+
+        let observation = Observation {
+            now: to_instant(time),
+            wall_time: time.with_timezone(&Local),
+            uptime: (time - since).to_std().unwrap_or_default(),
+            power: PowerStatus::default(),
+        };
+        let expired = monitor.expire_clients(observation.now, &mut clients);
+        let outcome = monitor.tick(&observation, &clients, &mut stats, &mut inhibitors);
+
+        for event in expired.iter().chain(&outcome.events) {
+            if event.level() <= Level::Info {
+                report.timeline.push((time, event.to_string()));
+            }
+
+            if let Event::Shutdown { idle_for } = event {
+                let last_seen = match &last_heartbeat {
+                    Some((hostname, at)) => format!(
+                        "last heartbeat from {hostname} at {}",
+                        at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+                    ),
+                    None => "no heartbeat since boot".to_string(),
+                };
+                report.power_offs.push(PowerOff {
+                    at: time,
+                    reason: format!(
+                        "shutdown timer ran for {} without active clients ({last_seen}), \
+                         no inhibitor active",
+                        format_duration(Duration::from_secs(idle_for.as_secs()))
+                    ),
+                });
+            }
+        }
+
+        // Tick again at the next deadline, as the server does
+        next_tick = outcome.wake_at.map_or(next_poll, |wake_at| {
+            (time + to_chrono(wake_at - observation.now)).min(next_poll)
+        });
+
+        if outcome.shutdown {
+            report.on_time += clipped(since, time, start, end);
+            power = Power::Off;
+            clients.clear();
+            last_heartbeat = None;
+            monitor = Monitor::new(config.clone());
+        }
+    }
+
+    report
+}
+
+fn power_on(time: DateTime<Utc>, boot_duration: Duration, next_tick: &mut DateTime<Utc>) -> Power {
+    let ready_at = time + to_chrono(boot_duration);
+    *next_tick = ready_at;
+    Power::On {
+        since: time,
+        ready_at,
+    }
+}
+
+/// Length of the interval `from..to` that lies within `start..end`
+fn clipped(
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Duration {
+    (to.min(end) - from.max(start)).to_std().unwrap_or_default()
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or_default()
+}
+
+impl SimulationReport {
+    pub fn print(&self) {
+        for (time, message) in &self.timeline {
+            println!(
+                "{}  {message}",
+                time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+
+        println!();
+        println!("Power-offs: {}", self.power_offs.len());
+        for power_off in &self.power_offs {
+            println!(
+                "  {}  {}",
+                power_off
+                    .at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S"),
+                power_off.reason
+            );
+        }
+
+        let share = if self.traced.is_zero() {
+            0.0
+        } else {
+            100.0 * self.on_time.as_secs_f64() / self.traced.as_secs_f64()
+        };
+        println!(
+            "On-time: {} of {} traced ({share:.1}%)",
+            format_duration(Duration::from_secs(self.on_time.as_secs())),
+            format_duration(Duration::from_secs(self.traced.as_secs()))
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PowerAction, PowerTier};
+    use chrono::TimeZone;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn heartbeat(timestamp: DateTime<Utc>) -> TraceEntry {
+        TraceEntry {
+            timestamp,
+            event: TraceEvent::Heartbeat {
+                hostname: "workstation".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn plays_out_every_power_tier_after_the_trace() {
+        let config = Config {
+            power_tiers: vec![
+                PowerTier {
+                    name: "quiet".to_string(),
+                    delay: 15 * MINUTE,
+                    action: PowerAction::Command {
+                        command: "true".to_string(),
+                        undo_command: None,
+                    },
+                },
+                PowerTier {
+                    name: "off".to_string(),
+                    delay: 120 * MINUTE,
+                    action: PowerAction::Shutdown,
+                },
+            ],
+            boot_grace_period: Duration::ZERO,
+            min_uptime: Duration::ZERO,
+            ..Config::default()
+        };
+        let start = Utc.with_ymd_and_hms(2025, 5, 1, 8, 0, 0).unwrap();
+        let trace = [
+            heartbeat(start),
+            heartbeat(start + chrono::Duration::minutes(30)),
+        ];
+
+        let report = simulate(Arc::new(config), &trace, 2 * MINUTE);
+
+        assert!(report
+            .timeline
+            .iter()
+            .any(|(_, message)| message.contains("entering power tier quiet")));
+        assert_eq!(report.power_offs.len(), 1);
+    }
+
+    #[test]
+    fn ticks_at_deadlines_rather_than_every_check_interval() {
+        let config = Config {
+            heartbeat_timeout: 2 * MINUTE,
+            shutdown_delay: 3 * MINUTE,
+            check_interval: 10 * MINUTE,
+            boot_grace_period: Duration::ZERO,
+            min_uptime: Duration::ZERO,
+            ..Config::default()
+        };
+        let start = Utc.with_ymd_and_hms(2025, 5, 1, 8, 0, 0).unwrap();
+
+        let report = simulate(Arc::new(config), &[heartbeat(start)], 2 * MINUTE);
+
+        let power_offs: Vec<_> = report.power_offs.iter().map(|off| off.at).collect();
+        assert_eq!(power_offs, vec![start + chrono::Duration::minutes(5)]);
+    }
+}
@@ -0,0 +1,439 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, NaiveDate, Timelike};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Uptime accounting for a single (local) calendar day
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DayStats {
+    /// Seconds the server was running on this day
+    pub on_secs: u64,
+    /// Seconds each client kept the NAS up, keyed by hostname
+    pub clients: BTreeMap<String, u64>,
+    /// Number of times each inhibitor prevented an expired shutdown timer from powering off
+    pub inhibitors: BTreeMap<String, u64>,
+    /// Number of shutdowns initiated by the server
+    #[serde(default)]
+    pub shutdowns: u32,
+}
+
+/// Persistent on-time statistics, keyed by local date
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Stats {
+    pub days: BTreeMap<NaiveDate, DayStats>,
+}
+
+/// Parameters for the energy-saving estimate
+#[derive(Debug, Clone, Copy)]
+pub struct EnergyModel {
+    pub power_watts: f64,
+    pub tariff_per_kwh: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DaySummary {
+    pub date: NaiveDate,
+    pub on_hours: f64,
+    pub off_hours: f64,
+    pub kwh_saved: f64,
+    pub cost_saved: f64,
+    pub clients: BTreeMap<String, f64>,
+    pub inhibitors: BTreeMap<String, u64>,
+    pub shutdowns: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub days: Vec<DaySummary>,
+    pub on_hours: f64,
+    pub off_hours: f64,
+    pub kwh_saved: f64,
+    pub cost_saved: f64,
+    pub clients: BTreeMap<String, f64>,
+    pub inhibitors: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    /// Load statistics from disk, starting empty if the file does not exist
+    /// yet or is damaged, e.g. by a power loss while it was written
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read stats from {}", path.display()))?;
+
+        match serde_json::from_str(&content) {
+            Ok(stats) => Ok(stats),
+            Err(e) => {
+                warn!(
+                    "Failed to parse stats from {}, starting over: {e}",
+                    path.display()
+                );
+                Ok(Self::default())
+            }
+        }
+    }
+
+    /// Write the statistics to a temporary file and rename it over `path`,
+    /// so that an interrupted write leaves the previous file intact
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+
+        let content = serde_json::to_string_pretty(self).context("Failed to serialize stats")?;
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let write = || -> std::io::Result<()> {
+            let mut file = File::create(&temporary)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temporary, path)
+        };
+        write().with_context(|| format!("Failed to write stats to {}", path.display()))
+    }
+
+    /// Account the `secs` of on-time up to `now` to the days they fall on, and
+    /// to every active client; an interval that crosses midnight is split there
+    pub fn record_uptime(&mut self, now: DateTime<Local>, secs: u64, active_clients: &[String]) {
+        let mut date = now.date_naive();
+        let mut available = u64::from(now.num_seconds_from_midnight());
+        let mut remaining = secs;
+
+        while remaining > 0 {
+            let on_day = remaining.min(available);
+            if on_day > 0 {
+                let day = self.days.entry(date).or_default();
+                day.on_secs += on_day;
+                for hostname in active_clients {
+                    *day.clients.entry(hostname.clone()).or_default() += on_day;
+                }
+            }
+            remaining -= on_day;
+
+            let Some(previous) = date.pred_opt() else {
+                break;
+            };
+            date = previous;
+            available = SECS_PER_DAY;
+        }
+    }
+
+    /// Count an inhibitor as the deciding factor for keeping the NAS on
+    pub fn record_inhibitor(&mut self, now: DateTime<Local>, inhibitor: &str) {
+        let day = self.days.entry(now.date_naive()).or_default();
+        *day.inhibitors.entry(inhibitor.to_string()).or_default() += 1;
+    }
+
+    pub fn record_shutdown(&mut self, now: DateTime<Local>) {
+        self.days.entry(now.date_naive()).or_default().shutdowns += 1;
+    }
+
+    /// Take back a shutdown recorded on the day of `now` that didn't take effect
+    pub fn forget_shutdown(&mut self, now: DateTime<Local>) {
+        if let Some(day) = self.days.get_mut(&now.date_naive()) {
+            day.shutdowns = day.shutdowns.saturating_sub(1);
+        }
+    }
+
+    /// Number of shutdowns initiated on the day of `now`
+    pub fn shutdowns_on(&self, now: DateTime<Local>) -> u32 {
+        self.days
+            .get(&now.date_naive())
+            .map_or(0, |day| day.shutdowns)
+    }
+
+    /// Summarize the last `days` days up to and including `now`.
+    ///
+    /// Days without any record are treated as fully powered off, so the
+    /// range is filled in from the first recorded day onwards.
+    pub fn report(
+        &self,
+        model: EnergyModel,
+        now: DateTime<Local>,
+        days: Option<usize>,
+    ) -> StatsReport {
+        let today = now.date_naive();
+        let first = self.days.keys().next().copied().unwrap_or(today).min(today);
+
+        let mut summaries: Vec<DaySummary> = first
+            .iter_days()
+            .take_while(|date| *date <= today)
+            .map(|date| {
+                let day = self.days.get(&date).cloned().unwrap_or_default();
+                let day_secs = if date == today {
+                    u64::from(now.num_seconds_from_midnight())
+                } else {
+                    SECS_PER_DAY
+                };
+                let off_hours = secs_to_hours(day_secs.saturating_sub(day.on_secs));
+                let kwh_saved = off_hours * model.power_watts / 1000.0;
+
+                DaySummary {
+                    date,
+                    on_hours: secs_to_hours(day.on_secs),
+                    off_hours,
+                    kwh_saved,
+                    cost_saved: kwh_saved * model.tariff_per_kwh,
+                    clients: day
+                        .clients
+                        .iter()
+                        .map(|(hostname, secs)| (hostname.clone(), secs_to_hours(*secs)))
+                        .collect(),
+                    inhibitors: day.inhibitors,
+                    shutdowns: day.shutdowns,
+                }
+            })
+            .collect();
+
+        if let Some(days) = days {
+            let skip = summaries.len().saturating_sub(days);
+            summaries.drain(..skip);
+        }
+
+        let mut report = StatsReport {
+            days: Vec::new(),
+            on_hours: 0.0,
+            off_hours: 0.0,
+            kwh_saved: 0.0,
+            cost_saved: 0.0,
+            clients: BTreeMap::new(),
+            inhibitors: BTreeMap::new(),
+        };
+
+        for day in &summaries {
+            report.on_hours += day.on_hours;
+            report.off_hours += day.off_hours;
+            report.kwh_saved += day.kwh_saved;
+            report.cost_saved += day.cost_saved;
+            for (hostname, hours) in &day.clients {
+                *report.clients.entry(hostname.clone()).or_default() += hours;
+            }
+            for (inhibitor, count) in &day.inhibitors {
+                *report.inhibitors.entry(inhibitor.clone()).or_default() += count;
+            }
+        }
+
+        report.days = summaries;
+        report
+    }
+}
+
+impl StatsReport {
+    /// Render the report as CSV with one row per day and one column per client and inhibitor
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("date,on_hours,off_hours,kwh_saved,cost_saved,shutdowns");
+        for hostname in self.clients.keys() {
+            let _ = write!(csv, ",{}", csv_field(&format!("client:{hostname}")));
+        }
+        for inhibitor in self.inhibitors.keys() {
+            let _ = write!(csv, ",{}", csv_field(&format!("inhibitor:{inhibitor}")));
+        }
+        csv.push('\n');
+
+        for day in &self.days {
+            let _ = write!(
+                csv,
+                "{},{:.2},{:.2},{:.3},{:.2},{}",
+                day.date, day.on_hours, day.off_hours, day.kwh_saved, day.cost_saved, day.shutdowns
+            );
+            for hostname in self.clients.keys() {
+                let _ = write!(csv, ",{:.2}", day.clients.get(hostname).unwrap_or(&0.0));
+            }
+            for inhibitor in self.inhibitors.keys() {
+                let _ = write!(csv, ",{}", day.inhibitors.get(inhibitor).unwrap_or(&0));
+            }
+            csv.push('\n');
+        }
+
+        csv
+    }
+
+    /// Render the report as a human-readable table
+    pub fn to_table(&self) -> String {
+        let mut table = format!(
+            "{:<10}  {:>8}  {:>9}  {:>9}  {:>10}\n",
+            "Date", "On (h)", "Off (h)", "Saved kWh", "Saved cost"
+        );
+
+        for day in &self.days {
+            let _ = writeln!(
+                table,
+                "{:<10}  {:>8.2}  {:>9.2}  {:>9.3}  {:>10.2}",
+                day.date, day.on_hours, day.off_hours, day.kwh_saved, day.cost_saved
+            );
+        }
+
+        let _ = writeln!(
+            table,
+            "{:<10}  {:>8.2}  {:>9.2}  {:>9.3}  {:>10.2}",
+            "Total", self.on_hours, self.off_hours, self.kwh_saved, self.cost_saved
+        );
+
+        if !self.clients.is_empty() {
+            table.push_str("\nClient on-time:\n");
+            for (hostname, hours) in &self.clients {
+                let _ = writeln!(table, "  {hostname:<30} {hours:>8.2} h");
+            }
+        }
+
+        if !self.inhibitors.is_empty() {
+            table.push_str("\nInhibitor decisions:\n");
+            for (inhibitor, count) in &self.inhibitors {
+                let _ = writeln!(table, "  {inhibitor:<30} {count:>8}");
+            }
+        }
+
+        table
+    }
+}
+
+fn secs_to_hours(secs: u64) -> f64 {
+    secs as f64 / 3600.0
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const MODEL: EnergyModel = EnergyModel {
+        power_watts: 50.0,
+        tariff_per_kwh: 0.3,
+    };
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2025, 1, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 1, day).unwrap()
+    }
+
+    fn on_day(on_secs: u64) -> DayStats {
+        DayStats {
+            on_secs,
+            ..DayStats::default()
+        }
+    }
+
+    #[test]
+    fn records_uptime_for_the_day_and_active_clients() {
+        let mut stats = Stats::default();
+        let clients = ["desktop".to_string()];
+        stats.record_uptime(at(1, 10, 0), 600, &clients);
+        stats.record_uptime(at(1, 11, 0), 300, &[]);
+
+        let day = &stats.days[&date(1)];
+        assert_eq!(day.on_secs, 900);
+        assert_eq!(day.clients["desktop"], 600);
+    }
+
+    #[test]
+    fn splits_uptime_at_midnight() {
+        let mut stats = Stats::default();
+        let clients = ["desktop".to_string()];
+        stats.record_uptime(at(2, 0, 10), 20 * 60, &clients);
+
+        assert_eq!(stats.days[&date(1)].on_secs, 600);
+        assert_eq!(stats.days[&date(1)].clients["desktop"], 600);
+        assert_eq!(stats.days[&date(2)].on_secs, 600);
+        assert_eq!(stats.days[&date(2)].clients["desktop"], 600);
+
+        // Right at midnight, it all belongs to the day before
+        stats.record_uptime(at(3, 0, 0), 60, &[]);
+        assert_eq!(stats.days[&date(2)].on_secs, 660);
+        assert!(!stats.days.contains_key(&date(3)));
+    }
+
+    #[test]
+    fn report_fills_gaps_and_counts_today_up_to_now() {
+        let mut stats = Stats::default();
+        stats.days.insert(date(1), on_day(6 * 3600));
+        stats.days.insert(date(3), on_day(2 * 3600));
+
+        let report = stats.report(MODEL, at(3, 12, 0), None);
+
+        let dates: Vec<_> = report.days.iter().map(|day| day.date).collect();
+        assert_eq!(dates, [date(1), date(2), date(3)]);
+        // A day without records was off throughout
+        assert_eq!(report.days[1].on_hours, 0.0);
+        assert_eq!(report.days[1].off_hours, 24.0);
+        // Only the hours of today that have passed count as off
+        assert_eq!(report.days[2].off_hours, 10.0);
+        assert_eq!(report.on_hours, 8.0);
+        assert_eq!(report.off_hours, 18.0 + 24.0 + 10.0);
+        assert!((report.kwh_saved - 52.0 * 0.05).abs() < 1e-9);
+        assert!((report.cost_saved - 52.0 * 0.05 * 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn report_keeps_only_the_last_days() {
+        let mut stats = Stats::default();
+        stats.days.insert(date(1), on_day(6 * 3600));
+        stats.days.insert(date(3), on_day(2 * 3600));
+
+        let report = stats.report(MODEL, at(3, 12, 0), Some(2));
+
+        let dates: Vec<_> = report.days.iter().map(|day| day.date).collect();
+        assert_eq!(dates, [date(2), date(3)]);
+        assert_eq!(report.on_hours, 2.0);
+        assert_eq!(report.off_hours, 24.0 + 10.0);
+    }
+
+    #[test]
+    fn csv_quotes_names_with_separators() {
+        let mut stats = Stats::default();
+        let clients = ["desk,top".to_string(), "\"laptop\"".to_string()];
+        stats.record_uptime(at(1, 1, 0), 3600, &clients);
+
+        let csv = stats.report(MODEL, at(1, 2, 0), None).to_csv();
+
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some(
+                "date,on_hours,off_hours,kwh_saved,cost_saved,shutdowns,\
+                 \"client:\"\"laptop\"\"\",\"client:desk,top\""
+            )
+        );
+        assert_eq!(
+            lines.next(),
+            Some("2025-01-01,1.00,1.00,0.050,0.01,0,1.00,1.00")
+        );
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn starts_over_from_a_damaged_file() {
+        let path = std::env::temp_dir().join(format!("nas-boot-stats-{}.json", std::process::id()));
+        fs::write(&path, "{\"days\": {\"2025-01-01\": {\"on_secs\": 3").unwrap();
+
+        let stats = Stats::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(stats.days.is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+//! Optional TLS for the HTTP listener, with a self-signed certificate whose
+//! fingerprint the clients pin
+
+use anyhow::{Context, Result};
+use axum::serve::Listener;
+use log::debug;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{crypto, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+use crate::guard::{Guard, GuardedListener, GuardedStream};
+
+/// SHA-256 fingerprint of a DER-encoded certificate, as pinned by the clients
+pub fn fingerprint(certificate: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, certificate);
+    let mut fingerprint = [0; 32];
+    fingerprint.copy_from_slice(digest.as_ref());
+    nas_boot_protocol::format_fingerprint(&fingerprint)
+}
+
+/// Load the certificate and key; returns the acceptor and the certificate's fingerprint
+pub fn acceptor(config: &TlsConfig) -> Result<(TlsAcceptor, String)> {
+    let certificates = CertificateDer::pem_file_iter(&config.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificate from {}", config.certificate))?;
+    let private_key = PrivateKeyDer::from_pem_file(&config.private_key)
+        .with_context(|| format!("Failed to read private key from {}", config.private_key))?;
+    let fingerprint = fingerprint(
+        certificates
+            .first()
+            .with_context(|| format!("No certificate in {}", config.certificate))?,
+    );
+
+    let server_config =
+        ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certificates, private_key)
+            .context("Invalid certificate or private key")?;
+
+    Ok((TlsAcceptor::from(Arc::new(server_config)), fingerprint))
+}
+
+/// Write a new self-signed certificate for `names` and its private key;
+/// replaces existing ones only with `force`, as clients pin the old certificate
+pub fn generate_cert(names: Vec<String>, config: &TlsConfig, force: bool) -> Result<()> {
+    let certificate_path = Path::new(&config.certificate);
+    let key_path = Path::new(&config.private_key);
+    for path in [certificate_path, key_path] {
+        if path.exists() && !force {
+            return Err(anyhow::anyhow!(
+                "{} already exists; pass --force to replace it, then pin the new certificate on every client",
+                path.display()
+            ));
+        }
+    }
+
+    let certified = rcgen::generate_simple_self_signed(names.clone())
+        .context("Failed to generate certificate")?;
+
+    if let Some(parent) = certificate_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    fs::write(certificate_path, certified.cert.pem())
+        .with_context(|| format!("Failed to write certificate to {}", config.certificate))?;
+
+    // Create the key readable by its owner only from the start, rather than
+    // narrowing its permissions once it's written
+    if force && key_path.exists() {
+        fs::remove_file(key_path)
+            .with_context(|| format!("Failed to remove old private key {}", config.private_key))?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_path)
+        .and_then(|mut file| file.write_all(certified.key_pair.serialize_pem().as_bytes()))
+        .with_context(|| format!("Failed to write private key to {}", config.private_key))?;
+
+    println!(
+        "Generated certificate for {} at: {}",
+        names.join(", "),
+        config.certificate
+    );
+    println!("Private key at: {}", config.private_key);
+    println!();
+    println!("Enable it in the server configuration with:");
+    println!("tls:");
+    println!("  certificate: \"{}\"", config.certificate);
+    println!("  private_key: \"{}\"", config.private_key);
+    println!();
+    println!("and pin it in the client configuration with:");
+    println!(
+        "server_cert_fingerprint: \"{}\"",
+        fingerprint(certified.cert.der())
+    );
+    Ok(())
+}
+
+type Connection = (TlsStream<GuardedStream<TcpStream>>, SocketAddr);
+
+/// Handshakes that may be in flight before the listener stops accepting
+const MAX_PENDING_CONNECTIONS: usize = 32;
+
+/// Listener completing the TLS handshake of each connection accepted by a
+/// [`GuardedListener`], without one slow handshake holding up the others
+pub struct TlsListener {
+    connections: mpsc::Receiver<Connection>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Start accepting on `listener`; a handshake not finished within
+    /// `handshake_timeout` is abandoned
+    pub fn new(
+        mut listener: GuardedListener,
+        acceptor: TlsAcceptor,
+        handshake_timeout: Duration,
+        guard: Arc<Guard>,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(MAX_PENDING_CONNECTIONS);
+
+        tokio::spawn(async move {
+            loop {
+                let Ok(permit) = sender.clone().reserve_owned().await else {
+                    return;
+                };
+                let (stream, peer) = listener.accept().await;
+                let acceptor = acceptor.clone();
+                let guard = guard.clone();
+
+                tokio::spawn(async move {
+                    match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            permit.send((stream, peer));
+                        }
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {peer} failed: {e}");
+                            guard.rejections.rejected("TLS handshake failed", peer.ip());
+                        }
+                        Err(_) => guard
+                            .rejections
+                            .rejected("TLS handshake timed out", peer.ip()),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<GuardedStream<TcpStream>>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accepting task only stops once this listener is gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
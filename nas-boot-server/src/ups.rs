@@ -0,0 +1,183 @@
+//! Client for the NUT upsd network protocol, which tells the monitor whether
+//! the NAS runs on mains or on battery
+
+use anyhow::{Context, Result};
+use humantime::format_duration;
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::{self, Instant};
+
+use crate::config::UpsConfig;
+
+/// Power supply as last reported by the UPS; mains power if there is no UPS
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PowerStatus {
+    /// Start of the current outage, if on battery
+    pub on_battery_since: Option<Instant>,
+    /// Battery charge in percent
+    pub battery_charge: Option<f64>,
+    pub low_battery: bool,
+}
+
+impl PowerStatus {
+    /// How long the current outage has lasted, if on battery
+    pub fn outage(&self, now: Instant) -> Option<Duration> {
+        self.on_battery_since
+            .map(|since| now.saturating_duration_since(since))
+    }
+}
+
+/// A single reading of the UPS variables the monitor cares about
+#[derive(Debug, Clone, PartialEq)]
+struct UpsReading {
+    on_battery: bool,
+    low_battery: bool,
+    battery_charge: Option<f64>,
+}
+
+/// Poll upsd forever, keeping `power` up to date, logging power events and
+/// notifying `wake` on changes
+pub async fn watch_ups(config: UpsConfig, power: Arc<Mutex<PowerStatus>>, wake: Arc<Notify>) {
+    let mut interval = time::interval(config.poll_interval);
+    let mut connection: Option<BufStream<TcpStream>> = None;
+    let mut reachable = true;
+
+    loop {
+        let now = interval.tick().await;
+
+        let reading = time::timeout(config.poll_interval, read_ups(&config, &mut connection)).await;
+        let reading = match reading {
+            Ok(Ok(reading)) => reading,
+            Ok(Err(e)) => {
+                if reachable {
+                    warn!("Failed to query UPS {}: {e:#}", config.name);
+                }
+                reachable = false;
+                connection = None;
+                continue;
+            }
+            Err(_) => {
+                if reachable {
+                    warn!("Timed out querying UPS {}", config.name);
+                }
+                reachable = false;
+                connection = None;
+                continue;
+            }
+        };
+        if !reachable {
+            info!("UPS {} is reachable again", config.name);
+            reachable = true;
+        }
+
+        let mut power = power.lock().await;
+        log_power_events(&config, &power, &reading, now);
+        let status = PowerStatus {
+            on_battery_since: match (reading.on_battery, power.on_battery_since) {
+                (true, Some(since)) => Some(since),
+                (true, None) => Some(now),
+                (false, _) => None,
+            },
+            battery_charge: reading.battery_charge,
+            low_battery: reading.low_battery,
+        };
+        if *power != status {
+            *power = status;
+            wake.notify_one();
+        }
+    }
+}
+
+fn log_power_events(
+    config: &UpsConfig,
+    previous: &PowerStatus,
+    reading: &UpsReading,
+    now: Instant,
+) {
+    let charge = reading
+        .battery_charge
+        .map(|charge| format!(" at {charge:.0}% charge"))
+        .unwrap_or_default();
+
+    match (previous.outage(now), reading.on_battery) {
+        (None, true) => warn!("Power outage: UPS {} is on battery{charge}", config.name),
+        (Some(outage), false) => info!(
+            "Power restored: UPS {} is back on mains after {}",
+            config.name,
+            format_duration(Duration::from_secs(outage.as_secs()))
+        ),
+        _ => {}
+    }
+
+    if reading.low_battery && !previous.low_battery {
+        warn!("UPS {} reports low battery{charge}", config.name);
+    }
+}
+
+async fn read_ups(
+    config: &UpsConfig,
+    connection: &mut Option<BufStream<TcpStream>>,
+) -> Result<UpsReading> {
+    let stream = match connection {
+        Some(stream) => stream,
+        None => {
+            let address = format!("{}:{}", config.host, config.port);
+            let stream = TcpStream::connect(&address)
+                .await
+                .with_context(|| format!("Failed to connect to upsd at {address}"))?;
+            connection.insert(BufStream::new(stream))
+        }
+    };
+
+    let status = get_var(stream, &config.name, "ups.status")
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("UPS does not report ups.status"))?;
+    let battery_charge = get_var(stream, &config.name, "battery.charge")
+        .await?
+        .and_then(|charge| charge.parse().ok());
+
+    // ups.status is a list of flags such as "OL", "OB DISCHRG" or "OB LB"
+    let flags: Vec<&str> = status.split_whitespace().collect();
+    Ok(UpsReading {
+        on_battery: flags.contains(&"OB"),
+        low_battery: flags.contains(&"LB"),
+        battery_charge,
+    })
+}
+
+/// Query a variable; `None` if the UPS doesn't support it
+async fn get_var(
+    stream: &mut BufStream<TcpStream>,
+    ups: &str,
+    variable: &str,
+) -> Result<Option<String>> {
+    stream
+        .write_all(format!("GET VAR {ups} {variable}\n").as_bytes())
+        .await?;
+    stream.flush().await?;
+
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(anyhow::anyhow!("upsd closed the connection"));
+    }
+    let line = line.trim_end();
+
+    // Answered with: VAR <ups> <variable> "<value>"
+    if line.starts_with("VAR ") {
+        let value = line
+            .split_once('"')
+            .and_then(|(_, rest)| rest.strip_suffix('"'))
+            .ok_or_else(|| anyhow::anyhow!("Malformed upsd response: {line}"))?;
+        return Ok(Some(value.to_string()));
+    }
+
+    match line.strip_prefix("ERR ") {
+        Some("VAR-NOT-SUPPORTED") => Ok(None),
+        Some(error) => Err(anyhow::anyhow!("upsd error for {variable}: {error}")),
+        None => Err(anyhow::anyhow!("Unexpected upsd response: {line}")),
+    }
+}